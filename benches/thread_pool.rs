@@ -0,0 +1,95 @@
+// Compares job dispatch throughput of rase::ThreadPool with the previous
+// design, where all workers shared a Mutex<mpsc::Receiver>.
+//
+// Run with `cargo bench --bench thread_pool`.
+
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use rase::ThreadPool;
+
+const JOBS: usize = 200_000;
+// Both pools drop jobs once 1000 of them are queued, so they are fed in
+// batches that always fit.
+const BATCH: usize = 500;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+enum Message {
+    NewJob(Job),
+    Terminate,
+}
+
+// The old pool, reduced to what matters for dispatch.
+struct MutexPool {
+    workers: Vec<thread::JoinHandle<()>>,
+    sender: mpsc::SyncSender<Message>,
+}
+
+impl MutexPool {
+    fn new(thread_count: usize) -> MutexPool {
+        let (sender, receiver) = mpsc::sync_channel(1000);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let workers = (0..thread_count).map(|_| {
+            let receiver: Arc<Mutex<mpsc::Receiver<Message>>> = Arc::clone(&receiver);
+            thread::spawn(move || loop {
+                let message = receiver.lock().unwrap().recv().unwrap();
+                match message {
+                    Message::NewJob(job) => job(),
+                    Message::Terminate => break,
+                }
+            })
+        }).collect();
+        MutexPool { workers, sender }
+    }
+
+    fn execute<F: FnOnce() + Send + 'static>(&self, f: F) {
+        self.sender.try_send(Message::NewJob(Box::new(f))).unwrap();
+    }
+}
+
+impl Drop for MutexPool {
+    fn drop(&mut self) {
+        for _ in &self.workers {
+            self.sender.send(Message::Terminate).unwrap();
+        }
+        for worker in self.workers.drain(..) {
+            worker.join().unwrap();
+        }
+    }
+}
+
+fn run<E: Fn(Box<dyn FnOnce() + Send>)>(execute: E) -> Duration {
+    let (done_tx, done_rx) = mpsc::channel();
+    let start = Instant::now();
+    for _ in 0..JOBS / BATCH {
+        for _ in 0..BATCH {
+            let done_tx = done_tx.clone();
+            execute(Box::new(move || {
+                done_tx.send(()).unwrap();
+            }));
+        }
+        for _ in 0..BATCH {
+            done_rx.recv().unwrap();
+        }
+    }
+    start.elapsed()
+}
+
+fn report(name: &str, threads: usize, elapsed: Duration) {
+    let rate = JOBS as f64 / elapsed.as_secs_f64();
+    println!("{:<12} {:>3} threads: {:>8.1} ms, {:>10.0} jobs/s",
+             name, threads, elapsed.as_secs_f64() * 1000.0, rate);
+}
+
+fn main() {
+    for &threads in &[1, 4, 16, 64] {
+        let pool = MutexPool::new(threads);
+        report("mutex+mpsc", threads, run(|job| pool.execute(job)));
+        drop(pool);
+
+        let pool = ThreadPool::new(threads);
+        report("rase", threads, run(|job| pool.execute(job)));
+    }
+}
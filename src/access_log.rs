@@ -0,0 +1,250 @@
+// Access log, one line per response, written to its own file so it doesn't
+// mix with the error log on stdout.
+//
+// The format is "common", "combined" or a template of nginx style
+// variables, e.g. "$remote_addr $status $body_bytes_sent $request_time".
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Instant, SystemTime};
+use log::error;
+use crate::config_parser::Config;
+use crate::http::{self, Request};
+use crate::lock;
+
+pub static COMMON_FORMAT: &str =
+    "$remote_addr - $remote_user [$time_local] \"$request\" $status $body_bytes_sent";
+pub static COMBINED_FORMAT: &str =
+    "$remote_addr - $remote_user [$time_local] \"$request\" $status $body_bytes_sent \
+     \"$http_referer\" \"$http_user_agent\"";
+
+pub static VARIABLES: &[&str] = &[
+    "remote_addr", "remote_user", "time_local", "time_iso8601", "request",
+    "request_method", "request_uri", "server_protocol", "host", "status",
+    "body_bytes_sent", "http_referer", "http_user_agent", "request_time",
+];
+
+static ACCESS_LOG: Mutex<Option<File>> = Mutex::new(None);
+
+/// What was sent for a request.
+pub(crate) struct Sent {
+    pub status: u16,
+    pub body_bytes: u64,
+}
+
+impl Sent {
+    // For responses that were put together in memory, head and body.
+    pub(crate) fn of(response: &[u8]) -> Sent {
+        let status = response.get(9..12)
+            .and_then(|s| std::str::from_utf8(s).ok())
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(0);
+        let head_len = response.windows(4).position(|w| w == b"\r\n\r\n")
+            .map(|p| p + 4)
+            .unwrap_or(response.len());
+        return Sent { status, body_bytes: (response.len() - head_len) as u64 };
+    }
+}
+
+/// Opens the access log for appending, an empty path disables it.
+pub fn open(path: &str) -> io::Result<()> {
+    if path.is_empty() {
+        return Ok(());
+    }
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    *lock(&ACCESS_LOG) = Some(file);
+    return Ok(());
+}
+
+/// The first variable of the template that doesn't exist, if any.
+pub fn find_unknown_variable(template: &str) -> Option<String> {
+    let mut unknown = None;
+    expand(template, |name, _| {
+        if unknown.is_none() && !name.is_empty() && !VARIABLES.contains(&name) {
+            unknown = Some(name.to_string());
+        }
+    });
+    return unknown;
+}
+
+pub(crate) fn log(request: &Request, remote_addr: Option<SocketAddr>,
+                  sent: &Sent, started: Instant, conf: &Config) {
+    let mut access_log = lock(&ACCESS_LOG);
+    let file = match access_log.as_mut() {
+        Some(f) => f,
+        None => return,
+    };
+    let mut line = String::new();
+    expand(&conf.access_log_format, |name, text| match name {
+        "" => line.push_str(text),
+        _ => line.push_str(&value(name, request, remote_addr, sent, started)),
+    });
+    line.push('\n');
+    // One write per line keeps lines from different threads whole.
+    if let Err(e) = file.write_all(line.as_bytes()) {
+        error!("Failed to write the access log: {}", e);
+    }
+}
+
+// For responses sent as they were put together, e.g. the canned errors.
+// Requests that couldn't be parsed are None and logged as "-".
+pub(crate) fn log_response(request: Option<&Request>, remote_addr: Option<SocketAddr>,
+                           response: &[u8], started: Instant, conf: &Config) {
+    let unparsed;
+    let request = match request {
+        Some(r) => r,
+        None => {
+            unparsed = http::get_default_request();
+            &unparsed
+        },
+    };
+    log(request, remote_addr, &Sent::of(response), started, conf);
+}
+
+// Calls `f` with ("", text) for literal text and (name, "") for each
+// $variable, in order.
+fn expand<F: FnMut(&str, &str)>(template: &str, mut f: F) {
+    let mut rest = template;
+    while let Some(p) = rest.find('$') {
+        f("", &rest[..p]);
+        let after = &rest[p + 1..];
+        let len = after.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .unwrap_or(after.len());
+        if len == 0 {
+            f("", "$");
+        } else {
+            f(&after[..len], "");
+        }
+        rest = &after[len..];
+    }
+    f("", rest);
+}
+
+fn value(name: &str, request: &Request, remote_addr: Option<SocketAddr>,
+         sent: &Sent, started: Instant) -> String {
+    return match name {
+        "remote_addr" => remote_addr.map(|a| a.ip().to_string())
+            .unwrap_or_else(|| "-".to_string()),
+        "remote_user" => "-".to_string(),
+        "time_local" => time_local(SystemTime::now()),
+        "time_iso8601" => format!("{}+00:00", utc_datetime(SystemTime::now())),
+        "request" if request.method.is_empty() => "-".to_string(),
+        "request" => escape(&format!("{} {} {}", request.method,
+                                     request.url_path, request.version)),
+        "request_method" => or_dash(&request.method),
+        "request_uri" => or_dash(&request.url_path),
+        "server_protocol" => or_dash(&request.version),
+        "host" => or_dash(&request.host),
+        "status" => sent.status.to_string(),
+        "body_bytes_sent" => sent.body_bytes.to_string(),
+        "http_referer" => or_dash(request.referer.as_deref().unwrap_or("")),
+        "http_user_agent" => or_dash(request.user_agent.as_deref().unwrap_or("")),
+        "request_time" => format!("{:.3}", started.elapsed().as_secs_f64()),
+        _ => String::new(),
+    };
+}
+
+fn or_dash(s: &str) -> String {
+    if s.is_empty() {
+        return "-".to_string();
+    }
+    return escape(s);
+}
+
+// Client supplied values can't break the line or the quoting, nginx style.
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' | '\\' => out.push_str(&format!("\\x{:02X}", c as u32)),
+            c if c.is_control() => {
+                let mut buf = [0; 4];
+                for b in c.encode_utf8(&mut buf).bytes() {
+                    out.push_str(&format!("\\x{:02X}", b));
+                }
+            },
+            c => out.push(c),
+        }
+    }
+    return out;
+}
+
+// Parts of an HTTP date, "Sun, 06 Nov 1994 08:49:37 GMT": day, month name,
+// year and time.
+fn date_parts(time: SystemTime) -> (String, String, String, String) {
+    let date = httpdate::fmt_http_date(time);
+    let mut parts = date.split(' ').skip(1).map(str::to_string);
+    let mut next = || parts.next().unwrap_or_default();
+    return (next(), next(), next(), next());
+}
+
+// 06/Nov/1994:08:49:37 +0000, the log is kept in UTC.
+fn time_local(time: SystemTime) -> String {
+    let (day, month, year, clock) = date_parts(time);
+    return format!("{}/{}/{}:{} +0000", day, month, year, clock);
+}
+
+// 1994-11-06T08:49:37, in UTC.
+pub(crate) fn utc_datetime(time: SystemTime) -> String {
+    let (day, month, year, clock) = date_parts(time);
+    let months = ["Jan", "Feb", "Mar", "Apr", "May", "Jun",
+                  "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    let month = months.iter().position(|m| *m == month).unwrap_or(0) + 1;
+    return format!("{}-{:02}-{}T{}", year, month, day, clock);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parts(template: &str) -> Vec<(String, String)> {
+        let mut parts = Vec::new();
+        expand(template, |name, text| parts.push((name.to_string(), text.to_string())));
+        // Empty literals between adjacent variables don't matter.
+        parts.retain(|(name, text)| !name.is_empty() || !text.is_empty());
+        return parts;
+    }
+
+    fn part(name: &str, text: &str) -> (String, String) {
+        return (name.to_string(), text.to_string());
+    }
+
+    #[test]
+    fn expands_variables_and_text() {
+        assert_eq!(parts("$remote_addr [$status] $request_time"),
+                   vec![part("remote_addr", ""), part("", " ["), part("status", ""),
+                        part("", "] "), part("request_time", "")]);
+        assert_eq!(parts("plain"), vec![part("", "plain")]);
+    }
+
+    #[test]
+    fn lone_dollars_are_text() {
+        assert_eq!(parts("$ 5$"), vec![part("", "$"), part("", " 5"), part("", "$")]);
+        assert_eq!(parts("$$status"), vec![part("", "$"), part("status", "")]);
+    }
+
+    #[test]
+    fn finds_unknown_variables() {
+        assert_eq!(find_unknown_variable(COMBINED_FORMAT), None);
+        assert_eq!(find_unknown_variable("$status $upstream_addr $nope").as_deref(),
+                   Some("upstream_addr"));
+        assert_eq!(find_unknown_variable("costs $ 5"), None);
+    }
+
+    #[test]
+    fn escapes_quotes_and_control_characters() {
+        assert_eq!(escape("Mozilla/5.0 (X11)"), "Mozilla/5.0 (X11)");
+        assert_eq!(escape("a\"b\\c"), "a\\x22b\\x5Cc");
+        assert_eq!(escape("a\r\nb\x7f"), "a\\x0D\\x0Ab\\x7F");
+        assert_eq!(escape("é"), "é");
+    }
+
+    #[test]
+    fn sent_of_raw_response() {
+        let sent = Sent::of(b"HTTP/1.1 404 Not Found\r\nContent-Length: 2\r\n\r\nno");
+        assert_eq!((sent.status, sent.body_bytes), (404, 2));
+    }
+}
@@ -0,0 +1,279 @@
+// Async counterpart of `server`, enabled with the "async" cargo feature.
+//
+// Connections are accepted and read on a tokio runtime and app handlers can
+// `.await` their own I/O. Static files are still served by the blocking
+// static handler on tokio's blocking thread pool. Existing sync apps run
+// unchanged through `SyncHandler`.
+
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Instant;
+use log::{LevelFilter, info, error};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{timeout, timeout_at};
+use crate::config_parser::{self, Config};
+use crate::http::{self, Request, HttpResponse};
+use crate::error_pages;
+use crate::access_log::{self, Sent};
+use crate::logger;
+use crate::server::{self, BodyBuffer, Head, HeadBuffer, HeadError};
+
+pub type HandlerFuture = Pin<Box<dyn Future<Output = HttpResponse> + Send>>;
+
+/// An async app. Any `Fn(Arc<Request>) -> impl Future<Output = HttpResponse>`
+/// is a handler, e.g. `|request| async move { ... }`.
+pub trait Handler: Send + Sync + 'static {
+    fn call(&self, request: Arc<Request>) -> HandlerFuture;
+}
+
+impl<F, Fut> Handler for F
+    where
+        F: Fn(Arc<Request>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = HttpResponse> + Send + 'static
+{
+    fn call(&self, request: Arc<Request>) -> HandlerFuture {
+        return Box::pin(self(request));
+    }
+}
+
+/// Adapts a sync `fn(&Request) -> HttpResponse` app. It runs on the blocking
+/// thread pool, so it may block without stalling other connections.
+pub struct SyncHandler(pub fn(request: &Request) -> HttpResponse);
+
+impl Handler for SyncHandler {
+    fn call(&self, request: Arc<Request>) -> HandlerFuture {
+        let app = self.0;
+        return Box::pin(async move {
+            let run = move || logger::with_request(&request, || app(&request));
+            match tokio::task::spawn_blocking(run).await {
+                Ok(response) => response,
+                // Let the connection task see the app's panic.
+                Err(e) => std::panic::resume_unwind(e.into_panic()),
+            }
+        });
+    }
+}
+
+pub fn run<H: Handler>(handler: H) {
+    let runtime = match tokio::runtime::Runtime::new() {
+        Ok(r) => r,
+        Err(e) => {
+            error!("Failed to start the async runtime: {}", e);
+            return;
+        },
+    };
+    runtime.block_on(init_listener(handler));
+}
+
+pub fn run_sync(app: fn(request: &Request) -> HttpResponse) {
+    run(SyncHandler(app));
+}
+
+pub async fn init_listener<H: Handler>(handler: H) {
+    log::set_logger(&logger::LOGGER).unwrap();
+    log::set_max_level(LevelFilter::Info);
+
+    let conf = Arc::new(config_parser::get_config());
+    logger::LOGGER.set_json(conf.log_format == "json");
+    if let Err(e) = access_log::open(&conf.access_log) {
+        error!("Failed to open the access log {}: {}", conf.access_log, e);
+        return;
+    }
+    let handler = Arc::new(handler);
+
+    let listener = match TcpListener::bind(&conf.address_full).await {
+        Err(why) => {
+            error!("{}", why);
+            return;
+        },
+        Ok(listener) => listener,
+    };
+
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                error!("{}", e);
+                continue;
+            },
+        };
+        let conf = Arc::clone(&conf);
+        let handler = Arc::clone(&handler);
+        tokio::spawn(async move {
+            handle_connection(stream, conf, handler).await;
+        });
+    }
+}
+
+async fn handle_connection<H: Handler>(mut stream: TcpStream, conf: Arc<Config>,
+                                       handler: Arc<H>) {
+    let started = Instant::now();
+    let (head, received) = match read_head(&mut stream, &conf).await {
+        Ok(r) => r,
+        Err(HeadError::Closed) => return,
+        Err(HeadError::Io(e)) => {
+            if e.kind() == io::ErrorKind::TimedOut {
+                info!("Timed out waiting for request headers");
+                reject(&mut stream, None, http::RESPONSE_408, started, &conf).await;
+            } else {
+                error!("Failed to read a request: {}", e);
+            }
+            return;
+        },
+        Err(HeadError::UriTooLong) => {
+            info!("Request line is longer than {} bytes", conf.max_header_size);
+            reject(&mut stream, None, http::RESPONSE_414, started, &conf).await;
+            return;
+        },
+        Err(HeadError::TooLarge) => {
+            info!("Request headers are larger than {} bytes", conf.max_header_size);
+            reject(&mut stream, None, http::RESPONSE_431, started, &conf).await;
+            return;
+        },
+    };
+    let request_str = String::from_utf8_lossy(&head);
+    let mut request = http::parse_request(&request_str, &conf);
+
+    if let Err(response) = server::check_body(&request, &conf) {
+        reject(&mut stream, Some(&request), response, started, &conf).await;
+        return;
+    }
+    if request.content_length > 0 {
+        match read_body(&mut stream, received, request.content_length,
+                        &conf).await {
+            Ok(body) => request.body = body,
+            Err(e) => {
+                info!("Failed to read request body: {}", e);
+                if e.kind() == io::ErrorKind::TimedOut {
+                    reject(&mut stream, Some(&request), http::RESPONSE_408, started,
+                           &conf).await;
+                }
+                return;
+            },
+        }
+    }
+    let remote_addr = stream.peer_addr().ok();
+
+    if request.is_static {
+        serve_static(stream, request, conf, started).await;
+        return;
+    }
+
+    let request = Arc::new(request);
+    // Running the handler as its own task turns a panic into an error here.
+    let future = handler.call(Arc::clone(&request));
+    let result = tokio::spawn(logger::in_request(&request, future)).await;
+    let response_raw = match result {
+        Ok(response) => server::http_response_to_str(&request, &response, &conf),
+        Err(e) => {
+            if !e.is_panic() {
+                return;
+            }
+            let payload = e.into_panic();
+            logger::with_request(&request, || server::log_panic(&request, &*payload));
+            error_pages::error_response(500, &request, &conf)
+        },
+    };
+    send(&mut stream, &response_raw, &conf).await;
+    let sent = Sent::of(&response_raw);
+    access_log::log(&request, remote_addr, &sent, started, &conf);
+    logger::log_completed(&request, sent.status, started);
+}
+
+// Sends one of the canned error responses, for a request that couldn't be
+// parsed when `request` is None, and logs it.
+async fn reject(stream: &mut TcpStream, request: Option<&Request>, response: &[u8],
+                started: Instant, conf: &Config) {
+    send(stream, response, conf).await;
+    access_log::log_response(request, stream.peer_addr().ok(), response, started, conf);
+}
+
+async fn serve_static(stream: TcpStream, request: Request, conf: Arc<Config>,
+                      started: Instant) {
+    let stream = match stream.into_std() {
+        Ok(s) => s,
+        Err(e) => {
+            error!("{}", e);
+            return;
+        },
+    };
+    let r = stream.set_nonblocking(false)
+        .and_then(|_| stream.set_write_timeout(server::timeout(conf.write_timeout)));
+    if let Err(e) = r {
+        error!("Failed to prepare a connection: {}", e);
+        return;
+    }
+    let remote_addr = stream.peer_addr().ok();
+    let _ = tokio::task::spawn_blocking(move || {
+        let sent = logger::with_request(&request, || {
+            server::serve_static(stream, &request, &conf)
+        });
+        access_log::log(&request, remote_addr, &sent, started, &conf);
+        logger::log_completed(&request, sent.status, started);
+    }).await;
+}
+
+// Same as server::read_head, but the whole head has to arrive before
+// header_read_timeout expires rather than each read.
+async fn read_head(stream: &mut TcpStream, conf: &Config
+                   ) -> Result<Head, HeadError> {
+    let deadline = server::timeout(conf.header_read_timeout)
+        .map(|t| tokio::time::Instant::now() + t);
+    let mut head = HeadBuffer::new();
+    let mut chunk = [0; 1024];
+    loop {
+        let read = stream.read(&mut chunk);
+        let n = match deadline {
+            Some(deadline) => match timeout_at(deadline, read).await {
+                Ok(r) => r,
+                Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut,
+                    "header_read_timeout expired")),
+            },
+            None => read.await,
+        }.map_err(HeadError::Io)?;
+        if let Some(r) = head.push(&chunk[..n], conf)? {
+            return Ok(r);
+        }
+    }
+}
+
+async fn read_body(stream: &mut TcpStream, received: Vec<u8>,
+                   content_length: usize, conf: &Config) -> io::Result<Vec<u8>> {
+    let mut body = BodyBuffer::new(received, content_length);
+    let mut buf = [0; 8192];
+    while !body.is_complete() {
+        let want = body.want(buf.len());
+        let read = stream.read(&mut buf[..want]);
+        let n = match server::timeout(conf.body_read_timeout) {
+            Some(t) => match timeout(t, read).await {
+                Ok(r) => r?,
+                Err(_) => return Err(io::Error::new(io::ErrorKind::TimedOut,
+                    "body_read_timeout expired")),
+            },
+            None => read.await?,
+        };
+        body.push(&buf[..n], conf)?;
+    }
+    return Ok(body.into_body());
+}
+
+async fn send(stream: &mut TcpStream, data: &[u8], conf: &Config) {
+    let write = async {
+        stream.write_all(data).await?;
+        stream.flush().await
+    };
+    let r = match server::timeout(conf.write_timeout) {
+        Some(t) => match timeout(t, write).await {
+            Ok(r) => r,
+            Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut,
+                "write_timeout expired")),
+        },
+        None => write.await,
+    };
+    if let Err(e) = r {
+        error!("Failed to send a response: {}", e);
+    }
+}
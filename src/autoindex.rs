@@ -0,0 +1,158 @@
+// Generated directory listings for static directories without an index
+// file, as an HTML page or, for `Accept: application/json`, as JSON.
+//
+// The order is picked with `?sort=name|size|mtime&order=asc|desc`,
+// directories always come first.
+
+use std::cmp::Ordering;
+use std::fs;
+use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::escape_json;
+
+struct Entry {
+    name: String,
+    is_dir: bool,
+    size: u64,
+    modified: Option<SystemTime>,
+}
+
+/// Returns the Content-Type and the body of the listing of `dir`, which is
+/// served at `url_path`. Symlinks for which `may_follow` is false are left
+/// out, as they couldn't be opened anyway.
+pub fn render<F: Fn(&str) -> bool>(dir: &str, url_path: &str, is_root: bool,
+                                   as_json: bool, show_hidden: bool,
+                                   may_follow: F
+                                   ) -> io::Result<(&'static str, Vec<u8>)> {
+    let (path, query) = match url_path.find('?') {
+        Some(p) => (&url_path[..p], &url_path[p + 1..]),
+        None => (url_path, ""),
+    };
+    let mut entries = read_entries(dir, show_hidden, may_follow)?;
+    sort_entries(&mut entries, query);
+    if as_json {
+        return Ok(("application/json", to_json(path, &entries).into_bytes()));
+    }
+    return Ok(("text/html; charset=utf-8",
+               to_html(path, is_root, &entries).into_bytes()));
+}
+
+fn read_entries<F: Fn(&str) -> bool>(dir: &str, show_hidden: bool,
+                                     may_follow: F) -> io::Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    for item in fs::read_dir(dir)? {
+        let item = item?;
+        let name = match item.file_name().into_string() {
+            Ok(n) => n,
+            // Such names can't be linked to reliably.
+            Err(_) => continue,
+        };
+        if !show_hidden && name.starts_with('.') {
+            continue;
+        }
+        let path = item.path();
+        let is_link = match fs::symlink_metadata(&path) {
+            Ok(m) => m.file_type().is_symlink(),
+            Err(_) => continue,
+        };
+        if is_link && !path.to_str().is_some_and(&may_follow) {
+            continue;
+        }
+        // A link that may be followed shows what it points to.
+        let meta = match fs::metadata(&path) {
+            Ok(m) => m,
+            Err(_) => continue,
+        };
+        entries.push(Entry {
+            name,
+            is_dir: meta.is_dir(),
+            size: if meta.is_dir() { 0 } else { meta.len() },
+            modified: meta.modified().ok(),
+        });
+    }
+    return Ok(entries);
+}
+
+fn sort_entries(entries: &mut [Entry], query: &str) {
+    let mut sort = "name";
+    let mut descending = false;
+    for pair in query.split('&') {
+        match pair.split_once('=') {
+            Some(("sort", v)) => sort = v,
+            Some(("order", v)) => descending = v == "desc",
+            _ => (),
+        }
+    }
+    entries.sort_by(|a, b| {
+        let by_key = match sort {
+            "size" => a.size.cmp(&b.size),
+            "mtime" => a.modified.cmp(&b.modified),
+            _ => Ordering::Equal,
+        }.then_with(|| a.name.cmp(&b.name));
+        let by_key = if descending { by_key.reverse() } else { by_key };
+        return b.is_dir.cmp(&a.is_dir).then(by_key);
+    });
+}
+
+fn to_html(path: &str, is_root: bool, entries: &[Entry]) -> String {
+    let title = format!("Index of {}", escape_html(path));
+    let mut html = format!("<!DOCTYPE html>\n<html>\n<head>\
+        <meta charset=\"utf-8\"><title>{0}</title></head>\n<body>\n\
+        <h1>{0}</h1>\n<table>\n<tr>\
+        <th><a href=\"?sort=name\">Name</a></th>\
+        <th><a href=\"?sort=size&amp;order=desc\">Size</a></th>\
+        <th><a href=\"?sort=mtime&amp;order=desc\">Modified</a></th></tr>\n",
+        title);
+    if !is_root {
+        html.push_str("<tr><td><a href=\"../\">../</a></td><td></td><td></td></tr>\n");
+    }
+    for entry in entries {
+        let suffix = if entry.is_dir { "/" } else { "" };
+        let size = match entry.is_dir {
+            true => "-".to_string(),
+            false => entry.size.to_string(),
+        };
+        let modified = entry.modified.map(httpdate::fmt_http_date)
+            .unwrap_or_default();
+        html.push_str(&format!(
+            "<tr><td><a href=\"{}{}\">{}{}</a></td><td>{}</td><td>{}</td></tr>\n",
+            encode_href(&entry.name), suffix, escape_html(&entry.name), suffix,
+            size, modified));
+    }
+    html.push_str("</table>\n</body>\n</html>\n");
+    return html;
+}
+
+fn to_json(path: &str, entries: &[Entry]) -> String {
+    let items: Vec<String> = entries.iter().map(|entry| {
+        let mtime = entry.modified
+            .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs().to_string())
+            .unwrap_or_else(|| "null".to_string());
+        format!("{{\"name\":{},\"type\":\"{}\",\"size\":{},\"mtime\":{}}}",
+                escape_json(&entry.name),
+                if entry.is_dir { "directory" } else { "file" },
+                entry.size, mtime)
+    }).collect();
+    return format!("{{\"path\":{},\"entries\":[{}]}}", escape_json(path),
+                   items.join(","));
+}
+
+fn escape_html(s: &str) -> String {
+    return s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+        .replace('"', "&quot;").replace('\'', "&#39;");
+}
+
+// Percent-encodes what would otherwise end or change the meaning of a
+// relative link.
+fn encode_href(name: &str) -> String {
+    let mut href = String::with_capacity(name.len());
+    for b in name.bytes() {
+        match b {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' |
+            b'-' | b'.' | b'_' | b'~' => href.push(b as char),
+            _ => href.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    return href;
+}
@@ -1,5 +1,3 @@
-use rase;
-
 fn main() {
     rase::server::run_empty();
 }
@@ -1,35 +1,119 @@
+use std::collections::HashMap;
 use std::fs::File;
 use std::path::Path;
 use std::io::prelude::*;
 use std::convert::TryFrom;
-use log::{error};
+use log::{error, warn};
+use crate::mime;
+use crate::access_log;
+
+#[derive(Clone)]
+pub struct CacheRule {
+    pub extensions: std::vec::Vec<String>,
+    pub path: Option<glob::Pattern>,
+    pub cache_control: String,
+}
+
+// A URL prefix served from a directory, with its own compression, caching
+// and listing settings.
+#[derive(Clone)]
+pub struct StaticMount {
+    pub url: String,
+    pub dir: String,
+    pub gzip_min_size: usize,
+    pub gzip_max_size: usize,
+    pub gzip_file_types: std::vec::Vec<String>,
+    pub precompressed_static: bool,
+    pub cache_rules: std::vec::Vec<CacheRule>,
+    pub fingerprinted_cache_control: String,
+    pub index_files: std::vec::Vec<String>,
+    pub autoindex: bool,
+    pub autoindex_hidden: bool,
+    pub fallback_file: String,
+    pub follow_symlinks: String,
+    pub serve_dotfiles: bool,
+}
 
 #[derive(Clone)]
 pub struct Config {
     pub address: String,
     pub port: String,
     pub address_full: String,
-    pub thread_count: usize,
+    pub io_mode: String,
+    pub reactor_threads: usize,
+    pub min_threads: usize,
+    pub max_threads: usize,
+    pub thread_idle_timeout: usize,
     pub test_param: usize,
-    pub static_dir: String,
-    pub static_url: String,
-    pub gzip_min_size: usize,
-    pub gzip_max_size: usize,
-    pub gzip_file_types: std::vec::Vec<String>,
+    pub header_read_timeout: usize,
+    pub body_read_timeout: usize,
+    pub write_timeout: usize,
+    pub body_min_rate: usize,
+    pub max_header_size: usize,
+    pub max_body_size: usize,
+    // Longest URL prefix first.
+    pub static_mounts: std::vec::Vec<StaticMount>,
+    pub static_cache_size: usize,
+    pub static_cache_max_file: usize,
+    // Extension (lowercase) -> type, overriding the builtin table.
+    pub mime_types: HashMap<String, String>,
+    pub default_mime_type: String,
+    pub sniff_mime_types: bool,
+    // Status -> page contents, read at startup.
+    pub error_pages: HashMap<u16, Vec<u8>>,
+    // Empty when there is no access log.
+    pub access_log: String,
+    // A template, "common" and "combined" are already expanded.
+    pub access_log_format: String,
+    pub log_format: String,
 }
 
+// Settings of the top-level static mount that [[static]] entries inherit
+// unless they set them themselves.
+static MOUNT_PARAMS: &[&str] = &[
+    "gzip_min_size", "gzip_max_size", "gzip_file_types", "precompressed_static",
+    "fingerprinted_cache_control", "index_files", "autoindex", "autoindex_hidden",
+    "fallback_file", "follow_symlinks", "serve_dotfiles",
+];
+
 fn get_def_config_toml() -> toml::Value {
     return (r#"
         address = '127.0.0.1'
         port = '8000'
         address_full = '127.0.0.1:8000'
-        thread_count = 3
+        io_mode = 'threads'
+        reactor_threads = 1
+        min_threads = 3
+        max_threads = 100
+        thread_idle_timeout = 60
         test_param = 5
+        header_read_timeout = 10
+        body_read_timeout = 30
+        write_timeout = 30
+        body_min_rate = 1024
+        max_header_size = 8192
+        max_body_size = 10485760
         static_dir = ''
         static_url = '/static/'
         gzip_min_size = 1024
         gzip_max_size = 1048576
         gzip_file_types = ["js", "txt", "html", "css"]
+        precompressed_static = false
+        fingerprinted_cache_control = ''
+        static_cache_size = 16777216
+        static_cache_max_file = 1048576
+        index_files = ["index.html", "index.htm"]
+        autoindex = false
+        autoindex_hidden = false
+        fallback_file = ''
+        follow_symlinks = 'if_owner_matches'
+        serve_dotfiles = false
+        default_mime_type = 'application/octet-stream'
+        mime_types_file = ''
+        sniff_mime_types = false
+        access_log = ''
+        access_log_format = 'combined'
+        log_format = 'text'
     "#).parse().unwrap();
 }
 
@@ -37,7 +121,7 @@ fn get_config_param_num(def_config_toml: &toml::Value,
                         user_config_toml: &toml::Value,
                         param: &String,
                         is_required: bool) -> usize {
-    if is_required && !user_config_toml.get(param).is_some() {
+    if is_required && user_config_toml.get(param).is_none() {
         panic!("Error parsing config file: param {} is required.", param);
     }
     let p = user_config_toml.get(param).unwrap_or(&def_config_toml[param]);
@@ -49,7 +133,7 @@ fn get_config_param_num(def_config_toml: &toml::Value,
         },
         Some(r) => {
             match usize::try_from(r) {
-                Err(err) => panic!(err),
+                Err(err) => panic!("{}", err),
                 Ok(r) => return r,
             }
         }
@@ -60,7 +144,7 @@ fn get_config_param_str(def_config_toml: &toml::Value,
                         user_config_toml: &toml::Value,
                         param: &String,
                         is_required: bool) -> String {
-    if is_required && !user_config_toml.get(param).is_some() {
+    if is_required && user_config_toml.get(param).is_none() {
         panic!("Error parsing config file: param {} is required.", param);
     }
     let p = user_config_toml.get(param).unwrap_or(&def_config_toml[param]);
@@ -70,11 +154,24 @@ fn get_config_param_str(def_config_toml: &toml::Value,
     };
 }
 
+fn get_config_param_bool(def_config_toml: &toml::Value,
+                         user_config_toml: &toml::Value,
+                         param: &String) -> bool {
+    let p = user_config_toml.get(param).unwrap_or(&def_config_toml[param]);
+    match p.as_bool() {
+        None => {
+            error!("Error in config file: param {} should be true or false.", param);
+            std::process::exit(0);
+        },
+        Some(r) => return r,
+    };
+}
+
 fn get_config_param_arr(def_config_toml: &toml::Value,
                         user_config_toml: &toml::Value,
                         param: &String,
                         is_required: bool) -> std::vec::Vec<String> {
-    if is_required && !user_config_toml.get(param).is_some() {
+    if is_required && user_config_toml.get(param).is_none() {
         error!("Error parsing config file: param {} is required.", param);
         std::process::exit(0);
     }
@@ -90,7 +187,7 @@ fn get_config_param_arr(def_config_toml: &toml::Value,
             match item.as_str() {
                 None => error!("CANNOT BE EMPTY BLABLA"),
                 Some(v) => {
-                    if v == "" {
+                    if v.is_empty() {
                         error!("Error in config file: param {} should not contain \
                         an empty string.", param);
                         std::process::exit(0);
@@ -108,9 +205,257 @@ fn get_config_param_arr(def_config_toml: &toml::Value,
     return parsed_arr;
 }
 
+// [[cache_rules]] entries, each matching either a list of extensions or a
+// URL path glob. The first matching rule wins.
+fn get_cache_rules(user_config_toml: &toml::Value) -> std::vec::Vec<CacheRule> {
+    let rules = match user_config_toml.get("cache_rules") {
+        None => return Vec::new(),
+        Some(r) => match r.as_array() {
+            None => {
+                error!("Error in config file: cache_rules should be an array \
+                        of tables.");
+                std::process::exit(0);
+            },
+            Some(r) => r,
+        },
+    };
+    let mut parsed_rules = Vec::new();
+    for rule in rules {
+        let cache_control = match rule.get("cache_control").and_then(|v| v.as_str()) {
+            Some(v) if !v.is_empty() => v.to_owned(),
+            _ => {
+                error!("Error in config file: every entry of cache_rules needs \
+                        a cache_control string.");
+                std::process::exit(0);
+            },
+        };
+        let extensions = match rule.get("extensions") {
+            None => Vec::new(),
+            Some(_) => get_config_param_arr(rule, rule,
+                                            &"extensions".to_string(), true),
+        };
+        let path = match rule.get("path").map(|v| v.as_str()) {
+            None => None,
+            Some(Some(p)) => match glob::Pattern::new(p) {
+                Ok(p) => Some(p),
+                Err(e) => {
+                    error!("Error in config file: bad cache_rules path {}: {}", p, e);
+                    std::process::exit(0);
+                },
+            },
+            Some(None) => {
+                error!("Error in config file: cache_rules path should be a string.");
+                std::process::exit(0);
+            },
+        };
+        if extensions.is_empty() && path.is_none() {
+            error!("Error in config file: every entry of cache_rules needs \
+                    either extensions or path.");
+            std::process::exit(0);
+        }
+        parsed_rules.push(CacheRule {
+            extensions: extensions.iter().map(|e| e.to_ascii_lowercase()).collect(),
+            path,
+            cache_control,
+        });
+    }
+    return parsed_rules;
+}
+
+// mime_types_file, if set, with [mime_types] on top of it. The latter maps
+// extensions, with or without the leading dot, to types.
+fn get_mime_types(def_config_toml: &toml::Value,
+                  user_config_toml: &toml::Value) -> HashMap<String, String> {
+    let path = get_config_param_str(def_config_toml, user_config_toml,
+                                    &"mime_types_file".to_string(), false);
+    let mut mime_types = HashMap::new();
+    if !path.is_empty() {
+        mime_types = match mime::load_mime_types(&path) {
+            Ok(m) => m,
+            Err(e) => {
+                error!("Couldn't read mime_types_file {}: {}", path, e);
+                std::process::exit(0);
+            },
+        };
+    }
+    let table = match user_config_toml.get("mime_types") {
+        None => return mime_types,
+        Some(t) => match t.as_table() {
+            None => {
+                error!("Error in config file: mime_types should be a table.");
+                std::process::exit(0);
+            },
+            Some(t) => t,
+        },
+    };
+    for (ext, mime_type) in table {
+        let mime_type = match mime_type.as_str() {
+            Some(m) if m.contains('/') => m,
+            _ => {
+                error!("Error in config file: mime_types.{} should be a type \
+                        like \"text/plain\".", ext);
+                std::process::exit(0);
+            },
+        };
+        let ext = ext.trim_start_matches('.').to_ascii_lowercase();
+        mime_types.insert(ext, mime_type.to_string());
+    }
+    return mime_types;
+}
+
+// error_pages = { 404 = "404.html", 500 = "50x.html" }. Relative paths are
+// relative to the working directory, like rase.toml itself.
+fn get_error_pages(user_config_toml: &toml::Value) -> HashMap<u16, Vec<u8>> {
+    let table = match user_config_toml.get("error_pages") {
+        None => return HashMap::new(),
+        Some(t) => match t.as_table() {
+            None => {
+                error!("Error in config file: error_pages should be a table.");
+                std::process::exit(0);
+            },
+            Some(t) => t,
+        },
+    };
+    let mut error_pages = HashMap::new();
+    for (code, path) in table {
+        let code = match code.parse::<u16>() {
+            Ok(c) if (400..600).contains(&c) => c,
+            _ => {
+                error!("Error in config file: error_pages key {} should be an \
+                        error status like 404.", code);
+                std::process::exit(0);
+            },
+        };
+        let path = match path.as_str() {
+            Some(p) => p,
+            None => {
+                error!("Error in config file: error_pages.{} should be a path.", code);
+                std::process::exit(0);
+            },
+        };
+        match std::fs::read(path) {
+            Ok(page) => error_pages.insert(code, page),
+            Err(e) => {
+                error!("Couldn't read error page {}: {}", path, e);
+                std::process::exit(0);
+            },
+        };
+    }
+    return error_pages;
+}
+
+fn get_access_log_format(def_config_toml: &toml::Value,
+                         user_config_toml: &toml::Value) -> String {
+    let format = get_config_param_str(def_config_toml, user_config_toml,
+                                      &"access_log_format".to_string(), false);
+    let format = match format.as_str() {
+        "common" => access_log::COMMON_FORMAT.to_string(),
+        "combined" => access_log::COMBINED_FORMAT.to_string(),
+        _ => format,
+    };
+    if let Some(name) = access_log::find_unknown_variable(&format) {
+        error!("Error in config file: access_log_format has an unknown \
+                variable ${}.", name);
+        std::process::exit(0);
+    }
+    return format;
+}
+
+// The top-level static_dir/static_url mount plus one per [[static]] entry.
+// The top-level one may be left out once there are [[static]] entries.
+fn get_static_mounts(def_config_toml: &toml::Value,
+                     user_config_toml: &toml::Value) -> std::vec::Vec<StaticMount> {
+    let entries = match user_config_toml.get("static") {
+        None => Vec::new(),
+        Some(e) => match e.as_array() {
+            None => {
+                error!("Error in config file: static should be an array of \
+                        tables.");
+                std::process::exit(0);
+            },
+            Some(e) => e.clone(),
+        },
+    };
+    let mut mount_defaults = def_config_toml.clone();
+    if let (Some(defaults), Some(user)) = (mount_defaults.as_table_mut(),
+                                           user_config_toml.as_table()) {
+        for param in MOUNT_PARAMS {
+            if let Some(v) = user.get(*param) {
+                defaults.insert(param.to_string(), v.clone());
+            }
+        }
+    }
+    let cache_rules = get_cache_rules(user_config_toml);
+    let mut mounts = Vec::new();
+    if entries.is_empty() || user_config_toml.get("static_dir").is_some() {
+        mounts.push(get_static_mount(&mount_defaults, user_config_toml,
+                                     "static_url", "static_dir", &cache_rules));
+    }
+    for entry in &entries {
+        mounts.push(get_static_mount(&mount_defaults, entry, "url", "dir",
+                                     &cache_rules));
+    }
+    for (i, mount) in mounts.iter().enumerate() {
+        if mounts[..i].iter().any(|m| m.url == mount.url) {
+            error!("Error in config file: static URL {} is used twice.", mount.url);
+            std::process::exit(0);
+        }
+    }
+    // Longest prefix first, so the most specific mount wins.
+    mounts.sort_by_key(|m| std::cmp::Reverse(m.url.len()));
+    return mounts;
+}
+
+fn get_static_mount(mount_defaults: &toml::Value, mount_toml: &toml::Value,
+                    url_param: &str, dir_param: &str,
+                    default_cache_rules: &[CacheRule]) -> StaticMount {
+    let mut mount = StaticMount {
+        // Required, so there is nothing to inherit.
+        url: get_config_param_str(mount_toml, mount_toml,
+                                  &url_param.to_string(), true),
+        dir: get_config_param_str(mount_toml, mount_toml,
+                                  &dir_param.to_string(), true),
+        gzip_min_size: get_config_param_num(mount_defaults, mount_toml,
+                                  &"gzip_min_size".to_string(), false),
+        gzip_max_size: get_config_param_num(mount_defaults, mount_toml,
+                                  &"gzip_max_size".to_string(), false),
+        gzip_file_types: get_config_param_arr(mount_defaults, mount_toml,
+                                  &"gzip_file_types".to_string(), false),
+        precompressed_static: get_config_param_bool(mount_defaults, mount_toml,
+                                  &"precompressed_static".to_string()),
+        cache_rules: match mount_toml.get("cache_rules") {
+            Some(_) => get_cache_rules(mount_toml),
+            None => default_cache_rules.to_vec(),
+        },
+        fingerprinted_cache_control: get_config_param_str(mount_defaults, mount_toml,
+                                  &"fingerprinted_cache_control".to_string(), false),
+        index_files: get_config_param_arr(mount_defaults, mount_toml,
+                                  &"index_files".to_string(), false),
+        autoindex: get_config_param_bool(mount_defaults, mount_toml,
+                                  &"autoindex".to_string()),
+        autoindex_hidden: get_config_param_bool(mount_defaults, mount_toml,
+                                  &"autoindex_hidden".to_string()),
+        fallback_file: get_config_param_str(mount_defaults, mount_toml,
+                                  &"fallback_file".to_string(), false),
+        follow_symlinks: get_config_param_str(mount_defaults, mount_toml,
+                                  &"follow_symlinks".to_string(), false),
+        serve_dotfiles: get_config_param_bool(mount_defaults, mount_toml,
+                                  &"serve_dotfiles".to_string()),
+    };
+    if !["never", "always", "if_owner_matches"].contains(&mount.follow_symlinks.as_str()) {
+        error!("Error in config file: follow_symlinks should be \"never\", \
+                \"always\" or \"if_owner_matches\".");
+        std::process::exit(0);
+    }
+    if !mount.url.ends_with('/') {
+        mount.url.push('/');
+    }
+    return mount;
+}
+
 pub fn get_config() ->  Config {
     let path = Path::new("rase.toml");
-    let mut file = match File::open(&path) {
+    let mut file = match File::open(path) {
         Err(why) => {
             error!("Couldn't open config file {}: {}\n\
                 Config file is required. You can find an example config \
@@ -135,43 +480,105 @@ pub fn get_config() ->  Config {
         },
         Ok(c) => c,
     };
-    let def_config_toml = get_def_config_toml();
-    
+    let mut def_config_toml = get_def_config_toml();
+    // thread_count is the old setting for a fixed-size pool. It is still
+    // honoured as the default for both min_threads and max_threads.
+    if let Some(thread_count) = user_config_toml.get("thread_count") {
+        warn!("thread_count is deprecated, use min_threads and max_threads.");
+        def_config_toml["min_threads"] = thread_count.clone();
+        def_config_toml["max_threads"] = thread_count.clone();
+    }
+
     let address = String::from(&get_config_param_str(&def_config_toml,
                                     &user_config_toml,
                                     &"address".to_string(), false));
-    let port = String::from(get_config_param_str(&def_config_toml,
-                                    &user_config_toml, 
-                                    &"port".to_string(), false));
+    let port = get_config_param_str(&def_config_toml,
+                                    &user_config_toml,
+                                    &"port".to_string(), false);
     let address_full = [&address, ":", &port].join("").to_string();
-    let mut config = Config {
-        address: address,
-        port: port,
-        address_full: address_full,
-        thread_count: get_config_param_num(&def_config_toml,
-                                    &user_config_toml, 
-                                    &"thread_count".to_string(), false),
+    let config = Config {
+        address,
+        port,
+        address_full,
+        io_mode: get_config_param_str(&def_config_toml,
+                                    &user_config_toml,
+                                    &"io_mode".to_string(), false),
+        reactor_threads: get_config_param_num(&def_config_toml,
+                                    &user_config_toml,
+                                    &"reactor_threads".to_string(), false),
+        min_threads: get_config_param_num(&def_config_toml,
+                                    &user_config_toml,
+                                    &"min_threads".to_string(), false),
+        max_threads: get_config_param_num(&def_config_toml,
+                                    &user_config_toml,
+                                    &"max_threads".to_string(), false),
+        thread_idle_timeout: get_config_param_num(&def_config_toml,
+                                    &user_config_toml,
+                                    &"thread_idle_timeout".to_string(), false),
         test_param: get_config_param_num(&def_config_toml,
                                     &user_config_toml,
                                     &"test_param".to_string(), false),
-        static_dir: String::from(&get_config_param_str(&def_config_toml,
+        header_read_timeout: get_config_param_num(&def_config_toml,
+                                    &user_config_toml,
+                                    &"header_read_timeout".to_string(), false),
+        body_read_timeout: get_config_param_num(&def_config_toml,
+                                    &user_config_toml,
+                                    &"body_read_timeout".to_string(), false),
+        write_timeout: get_config_param_num(&def_config_toml,
+                                    &user_config_toml,
+                                    &"write_timeout".to_string(), false),
+        body_min_rate: get_config_param_num(&def_config_toml,
+                                    &user_config_toml,
+                                    &"body_min_rate".to_string(), false),
+        max_header_size: get_config_param_num(&def_config_toml,
+                                    &user_config_toml,
+                                    &"max_header_size".to_string(), false),
+        max_body_size: get_config_param_num(&def_config_toml,
+                                    &user_config_toml,
+                                    &"max_body_size".to_string(), false),
+        static_mounts: get_static_mounts(&def_config_toml, &user_config_toml),
+        static_cache_size: get_config_param_num(&def_config_toml,
                                     &user_config_toml,
-                                    &"static_dir".to_string(), true)),
-        static_url: String::from(&get_config_param_str(&def_config_toml,
+                                    &"static_cache_size".to_string(), false),
+        static_cache_max_file: get_config_param_num(&def_config_toml,
                                     &user_config_toml,
-                                    &"static_url".to_string(), true)),
-        gzip_min_size: get_config_param_num(&def_config_toml,
-                                    &user_config_toml, 
-                                    &"gzip_min_size".to_string(), false),
-        gzip_max_size: get_config_param_num(&def_config_toml,
-                                    &user_config_toml, 
-                                    &"gzip_max_size".to_string(), false),
-        gzip_file_types: get_config_param_arr(&def_config_toml,
+                                    &"static_cache_max_file".to_string(), false),
+        mime_types: get_mime_types(&def_config_toml, &user_config_toml),
+        default_mime_type: get_config_param_str(&def_config_toml,
                                     &user_config_toml,
-                                    &"gzip_file_types".to_string(), false),
+                                    &"default_mime_type".to_string(), false),
+        sniff_mime_types: get_config_param_bool(&def_config_toml,
+                                    &user_config_toml,
+                                    &"sniff_mime_types".to_string()),
+        error_pages: get_error_pages(&user_config_toml),
+        access_log: get_config_param_str(&def_config_toml, &user_config_toml,
+                                         &"access_log".to_string(), false),
+        access_log_format: get_access_log_format(&def_config_toml, &user_config_toml),
+        log_format: get_config_param_str(&def_config_toml, &user_config_toml,
+                                         &"log_format".to_string(), false),
     };
-    if !config.static_url.ends_with("/") {
-        config.static_url.push_str("/");
+    if config.io_mode != "threads" && config.io_mode != "epoll" {
+        error!("Error in config file: io_mode should be either \"threads\" \
+                or \"epoll\".");
+        std::process::exit(0);
+    }
+    if config.log_format != "text" && config.log_format != "json" {
+        error!("Error in config file: log_format should be either \"text\" \
+                or \"json\".");
+        std::process::exit(0);
+    }
+    if config.reactor_threads == 0 {
+        error!("Error in config file: reactor_threads should be bigger than 0.");
+        std::process::exit(0);
+    }
+    if config.max_threads == 0 {
+        error!("Error in config file: max_threads should be bigger than 0.");
+        std::process::exit(0);
+    }
+    if config.min_threads > config.max_threads {
+        error!("Error in config file: min_threads should not be bigger than \
+                max_threads.");
+        std::process::exit(0);
     }
     return config;
 }
@@ -14,7 +14,7 @@ pub fn default_app(request: &Request) -> HttpResponse {
                         request.url_path);
     let r = HttpResponse {
         code: 200,
-        content: content,
+        content,
     };
     return r;
 }
@@ -0,0 +1,176 @@
+// Content-coding negotiation (Accept-Encoding with q-values) and the
+// encoders used for on-the-fly compression. Brotli compression needs the
+// "brotli" cargo feature; precompressed .br files can be served without it.
+
+use std::io;
+use std::io::prelude::*;
+use libflate::{gzip, zlib};
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+    Deflate,
+    Identity,
+}
+
+/// Encodings rase can apply itself, in order of preference.
+#[cfg(feature = "brotli")]
+pub static ON_THE_FLY: &[Encoding] = &[Encoding::Brotli, Encoding::Gzip,
+                                       Encoding::Deflate];
+#[cfg(not(feature = "brotli"))]
+pub static ON_THE_FLY: &[Encoding] = &[Encoding::Gzip, Encoding::Deflate];
+
+// Brotli at its default quality of 11 is too slow for per-request use.
+#[cfg(feature = "brotli")]
+const BROTLI_QUALITY: u32 = 5;
+
+impl Encoding {
+    /// Value for the Content-Encoding header.
+    pub fn name(&self) -> &'static str {
+        return match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+            Encoding::Identity => "identity",
+        };
+    }
+}
+
+/// Picks the best of `available` for the given Accept-Encoding header. Ties
+/// are settled by the order of `available`. Falls back to identity when
+/// nothing acceptable is available, as most servers do instead of a 406.
+pub fn negotiate(accept_encoding: Option<&str>, available: &[Encoding]) -> Encoding {
+    let accept_encoding = match accept_encoding {
+        // No header means the client doesn't care, don't compress then.
+        None => return Encoding::Identity,
+        Some(a) => a,
+    };
+    let mut best = Encoding::Identity;
+    let mut best_q = 0.0;
+    for encoding in available {
+        let q = get_qvalue(accept_encoding, *encoding);
+        if q > best_q {
+            best = *encoding;
+            best_q = q;
+        }
+    }
+    return best;
+}
+
+/// Whether the client accepts `encoding` at all (q > 0).
+pub fn is_accepted(accept_encoding: &str, encoding: Encoding) -> bool {
+    return get_qvalue(accept_encoding, encoding) > 0.0;
+}
+
+fn get_qvalue(accept_encoding: &str, encoding: Encoding) -> f32 {
+    let mut star = None;
+    for item in accept_encoding.split(',') {
+        let mut parts = item.split(';');
+        let coding = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+        if coding.is_empty() {
+            continue;
+        }
+        let q = parts.filter_map(|p| p.split_once('='))
+            .filter(|(name, _)| name.trim().eq_ignore_ascii_case("q"))
+            .find_map(|(_, v)| v.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        if coding == "*" {
+            star = Some(q);
+        } else if coding == encoding.name() ||
+                  (coding == "x-gzip" && encoding == Encoding::Gzip) {
+            return q;
+        }
+    }
+    return match (star, encoding) {
+        (Some(q), _) => q,
+        // identity is acceptable unless it is excluded explicitly.
+        (None, Encoding::Identity) => 1.0,
+        (None, _) => 0.0,
+    };
+}
+
+pub fn encode(encoding: Encoding, data: &[u8]) -> io::Result<Vec<u8>> {
+    return match encoding {
+        Encoding::Gzip => {
+            let mut encoder = gzip::Encoder::new(Vec::new())?;
+            encoder.write_all(data)?;
+            encoder.finish().into_result()
+        },
+        Encoding::Deflate => {
+            let mut encoder = zlib::Encoder::new(Vec::new())?;
+            encoder.write_all(data)?;
+            encoder.finish().into_result()
+        },
+        Encoding::Brotli => encode_brotli(data),
+        Encoding::Identity => Ok(data.to_vec()),
+    };
+}
+
+#[cfg(feature = "brotli")]
+fn encode_brotli(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    {
+        let mut writer = brotli::CompressorWriter::new(&mut out, 4096,
+                                                       BROTLI_QUALITY, 22);
+        writer.write_all(data)?;
+    }
+    return Ok(out);
+}
+
+#[cfg(not(feature = "brotli"))]
+fn encode_brotli(_data: &[u8]) -> io::Result<Vec<u8>> {
+    return Err(io::Error::new(io::ErrorKind::Unsupported,
+                              "rase was built without the brotli feature"));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL: &[Encoding] = &[Encoding::Brotli, Encoding::Gzip, Encoding::Deflate,
+                               Encoding::Identity];
+
+    #[test]
+    fn highest_q_wins() {
+        assert_eq!(negotiate(Some("gzip;q=0.5, br;q=0.8, identity;q=0.1"), ALL),
+                   Encoding::Brotli);
+        assert_eq!(negotiate(Some("gzip, deflate;q=0.9"), ALL), Encoding::Gzip);
+        assert_eq!(negotiate(Some("x-gzip"), ALL), Encoding::Gzip);
+        // A tie goes to the order of `available`.
+        assert_eq!(negotiate(Some("gzip, br"), ALL), Encoding::Brotli);
+    }
+
+    #[test]
+    fn q_zero_excludes() {
+        assert_eq!(negotiate(Some("br;q=0, gzip"), ALL), Encoding::Gzip);
+        assert_eq!(negotiate(Some("gzip;q=0"), &[Encoding::Gzip, Encoding::Identity]),
+                   Encoding::Identity);
+        assert!(!is_accepted("gzip;q=0", Encoding::Gzip));
+        assert!(!is_accepted("identity;q=0", Encoding::Identity));
+    }
+
+    #[test]
+    fn q_parameter_is_lenient() {
+        assert!(!is_accepted("gzip;Q=0", Encoding::Gzip));
+        assert!(!is_accepted("gzip; q = 0", Encoding::Gzip));
+        assert!(is_accepted("gzip;level=0", Encoding::Gzip));
+        assert!(is_accepted("gzip;q=nope", Encoding::Gzip));
+    }
+
+    #[test]
+    fn star() {
+        assert_eq!(negotiate(Some("*"), ALL), Encoding::Brotli);
+        assert_eq!(negotiate(Some("*;q=0.1, gzip"), ALL), Encoding::Gzip);
+        assert_eq!(negotiate(Some("*;q=0"), ALL), Encoding::Identity);
+        assert!(!is_accepted("*;q=0", Encoding::Identity));
+        assert!(is_accepted("br", Encoding::Identity));
+        assert!(!is_accepted("br", Encoding::Gzip));
+    }
+
+    #[test]
+    fn no_header_means_identity() {
+        assert_eq!(negotiate(None, ALL), Encoding::Identity);
+        assert_eq!(negotiate(Some(""), ALL), Encoding::Identity);
+    }
+}
@@ -0,0 +1,61 @@
+// Error responses for static files and apps. The body comes from the app's
+// error renderer if one is registered and returns a page, otherwise from
+// the error_pages file configured for the status, otherwise it's the
+// builtin one.
+
+use std::io::prelude::*;
+use std::net::TcpStream;
+use std::panic;
+use std::sync::RwLock;
+use log::error;
+use crate::access_log::Sent;
+use crate::config_parser::Config;
+use crate::http::{self, Request};
+use crate::server;
+
+/// Renders the HTML body for an error status, or None to fall back to the
+/// configured error page.
+pub type ErrorRenderer = fn(code: u16, request: &Request) -> Option<String>;
+
+static ERROR_RENDERER: RwLock<Option<ErrorRenderer>> = RwLock::new(None);
+
+/// Registers the app's error renderer, replacing the previous one.
+pub fn set_error_renderer(renderer: ErrorRenderer) {
+    *ERROR_RENDERER.write().unwrap_or_else(|e| e.into_inner()) = Some(renderer);
+}
+
+// Also used after a handler has panicked, so it must not panic itself.
+pub(crate) fn error_response(code: u16, request: &Request, conf: &Config) -> Vec<u8> {
+    let renderer = *ERROR_RENDERER.read().unwrap_or_else(|e| e.into_inner());
+    let rendered = renderer.and_then(|render| {
+        match panic::catch_unwind(panic::AssertUnwindSafe(|| render(code, request))) {
+            Ok(page) => page,
+            Err(_) => {
+                error!("The error renderer panicked for status {}", code);
+                None
+            },
+        }
+    });
+    let body = match (rendered, conf.error_pages.get(&code)) {
+        (Some(page), _) => page.into_bytes(),
+        (None, Some(page)) => page.clone(),
+        (None, None) => {
+            format!("<h1>{} {}</h1>", code, http::reason_phrase(code)).into_bytes()
+        },
+    };
+    let head = format!("HTTP/1.1 {} {}\r\n\
+        Content-Length: {}\r\n\
+        Content-Type: text/html; charset=utf-8\r\n\
+        X-Content-Type-Options: nosniff\r\n\r\n",
+        code, http::reason_phrase(code), body.len());
+    return [head.into_bytes(), body].concat();
+}
+
+pub(crate) fn send_error(mut stream: &TcpStream, code: u16, request: &Request,
+                         conf: &Config) -> Sent {
+    let response = error_response(code, request, conf);
+    server::mark_response_started();
+    let _ = stream.write_all(&response);
+    let _ = stream.flush();
+    return Sent::of(&response);
+}
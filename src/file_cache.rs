@@ -0,0 +1,199 @@
+// In-memory LRU cache of static file bodies, raw or compressed, so hot
+// assets are neither re-read nor re-compressed on every hit.
+//
+// Entries remember the mtime and size of the file they were made from and
+// are dropped as soon as the file on disk no longer matches.
+
+use std::collections::BTreeMap;
+use std::fs::Metadata;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+use crate::encoding::Encoding;
+use crate::lock;
+
+pub static STATIC_CACHE: FileCache = FileCache::new();
+
+type Key = (String, Encoding);
+
+pub struct FileCache {
+    inner: Mutex<Inner>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+struct Inner {
+    entries: BTreeMap<Key, Entry>,
+    // Last use tick -> key, the first one is the least recently used.
+    recency: BTreeMap<u64, Key>,
+    tick: u64,
+    size: usize,
+}
+
+struct Entry {
+    modified: Option<SystemTime>,
+    file_size: u64,
+    body: Arc<Vec<u8>>,
+    last_used: u64,
+}
+
+impl FileCache {
+    pub const fn new() -> FileCache {
+        return FileCache {
+            inner: Mutex::new(Inner {
+                entries: BTreeMap::new(),
+                recency: BTreeMap::new(),
+                tick: 0,
+                size: 0,
+            }),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        };
+    }
+
+    /// The body of `path` in `encoding`, if it is cached and the file hasn't
+    /// changed since.
+    pub fn get(&self, path: &str, encoding: Encoding,
+               meta: &Metadata) -> Option<Arc<Vec<u8>>> {
+        let mut inner = lock(&self.inner);
+        let key = (path.to_string(), encoding);
+        let is_fresh = inner.entries.get(&key).map(|entry| {
+            entry.modified == meta.modified().ok() && entry.file_size == meta.len()
+        });
+        let body = match is_fresh {
+            Some(true) => Some(inner.touch(&key)),
+            Some(false) => {
+                inner.remove(&key);
+                None
+            },
+            None => None,
+        };
+        match body {
+            Some(_) => self.hits.fetch_add(1, Ordering::Relaxed),
+            None => self.misses.fetch_add(1, Ordering::Relaxed),
+        };
+        return body;
+    }
+
+    /// Caches a body, evicting the least recently used entries until the
+    /// cache fits into `max_size` bytes again.
+    pub fn insert(&self, path: &str, encoding: Encoding, meta: &Metadata,
+                  body: Arc<Vec<u8>>, max_size: usize) {
+        if body.len() > max_size {
+            return;
+        }
+        let mut inner = lock(&self.inner);
+        let key = (path.to_string(), encoding);
+        inner.remove(&key);
+        inner.tick += 1;
+        let tick = inner.tick;
+        inner.size += body.len();
+        inner.recency.insert(tick, key.clone());
+        inner.entries.insert(key, Entry {
+            modified: meta.modified().ok(),
+            file_size: meta.len(),
+            body,
+            last_used: tick,
+        });
+        while inner.size > max_size {
+            let oldest = match inner.recency.values().next() {
+                Some(k) => k.clone(),
+                None => break,
+            };
+            inner.remove(&oldest);
+        }
+    }
+
+    pub fn hits(&self) -> u64 {
+        return self.hits.load(Ordering::Relaxed);
+    }
+
+    pub fn misses(&self) -> u64 {
+        return self.misses.load(Ordering::Relaxed);
+    }
+
+    /// Total size of the cached bodies in bytes.
+    pub fn size(&self) -> usize {
+        return lock(&self.inner).size;
+    }
+}
+
+impl Default for FileCache {
+    fn default() -> FileCache {
+        return FileCache::new();
+    }
+}
+
+impl Inner {
+    fn touch(&mut self, key: &Key) -> Arc<Vec<u8>> {
+        self.tick += 1;
+        let tick = self.tick;
+        let entry = self.entries.get_mut(key).unwrap();
+        let last_used = entry.last_used;
+        entry.last_used = tick;
+        let body = Arc::clone(&entry.body);
+        self.recency.remove(&last_used);
+        self.recency.insert(tick, key.clone());
+        return body;
+    }
+
+    fn remove(&mut self, key: &Key) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.recency.remove(&entry.last_used);
+            self.size -= entry.body.len();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn body(len: usize) -> Arc<Vec<u8>> {
+        return Arc::new(vec![b'x'; len]);
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let cache = FileCache::new();
+        let meta = fs::metadata("Cargo.toml").unwrap();
+        cache.insert("a", Encoding::Identity, &meta, body(40), 100);
+        cache.insert("b", Encoding::Identity, &meta, body(40), 100);
+        assert!(cache.get("a", Encoding::Identity, &meta).is_some());
+        // "b" is now the least recently used.
+        cache.insert("c", Encoding::Identity, &meta, body(40), 100);
+        assert!(cache.get("b", Encoding::Identity, &meta).is_none());
+        assert!(cache.get("a", Encoding::Identity, &meta).is_some());
+        assert!(cache.get("c", Encoding::Identity, &meta).is_some());
+        assert_eq!(cache.size(), 80);
+        assert_eq!((cache.hits(), cache.misses()), (3, 1));
+    }
+
+    #[test]
+    fn accounts_bytes() {
+        let cache = FileCache::new();
+        let meta = fs::metadata("Cargo.toml").unwrap();
+        cache.insert("a", Encoding::Identity, &meta, body(10), 100);
+        cache.insert("a", Encoding::Gzip, &meta, body(5), 100);
+        assert_eq!(cache.size(), 15);
+        // Replacing an entry doesn't count it twice.
+        cache.insert("a", Encoding::Identity, &meta, body(20), 100);
+        assert_eq!(cache.size(), 25);
+        // Bodies larger than the whole cache aren't kept.
+        cache.insert("big", Encoding::Identity, &meta, body(101), 100);
+        assert_eq!(cache.size(), 25);
+        assert!(cache.get("big", Encoding::Identity, &meta).is_none());
+    }
+
+    #[test]
+    fn drops_entries_for_changed_files() {
+        let cache = FileCache::new();
+        let meta = fs::metadata("Cargo.toml").unwrap();
+        let other = fs::metadata("src/lib.rs").unwrap();
+        cache.insert("a", Encoding::Identity, &meta, body(10), 100);
+        assert!(cache.get("a", Encoding::Identity, &other).is_none());
+        assert_eq!(cache.size(), 0);
+        assert!(cache.get("a", Encoding::Identity, &meta).is_none());
+    }
+}
@@ -1,23 +1,70 @@
-use std::path::Path;
+use std::path::{Component, Path, PathBuf};
 use std::io::Write;
 use std::net::TcpStream;
-use path_clean::{PathClean};
-use crate::config_parser::Config;
+use std::sync::atomic::{AtomicU64, Ordering};
+use crate::config_parser::{Config, StaticMount};
 
-pub static RESPONSE_404: &'static [u8] = b"HTTP/1.1 404 Not Found\r\n\
-        Content-Length: 22\r\n\
-        Content-Type: text/html\r\n\r\n\
-        <h1>404 Not found</h1>";
+pub static RESPONSE_400: &[u8] = b"HTTP/1.1 400 Bad Request\r\n\
+        Content-Length: 24\r\n\
+        Content-Type: text/html\r\n\
+        X-Content-Type-Options: nosniff\r\n\
+        Connection: close\r\n\r\n\
+        <h1>400 Bad Request</h1>";
+
+pub static RESPONSE_408: &[u8] = b"HTTP/1.1 408 Request Timeout\r\n\
+        Content-Length: 28\r\n\
+        Content-Type: text/html\r\n\
+        X-Content-Type-Options: nosniff\r\n\
+        Connection: close\r\n\r\n\
+        <h1>408 Request Timeout</h1>";
+
+pub static RESPONSE_413: &[u8] = b"HTTP/1.1 413 Content Too Large\r\n\
+        Content-Length: 30\r\n\
+        Content-Type: text/html\r\n\
+        X-Content-Type-Options: nosniff\r\n\
+        Connection: close\r\n\r\n\
+        <h1>413 Content Too Large</h1>";
+
+pub static RESPONSE_414: &[u8] = b"HTTP/1.1 414 URI Too Long\r\n\
+        Content-Length: 25\r\n\
+        Content-Type: text/html\r\n\
+        X-Content-Type-Options: nosniff\r\n\
+        Connection: close\r\n\r\n\
+        <h1>414 URI Too Long</h1>";
+
+pub static RESPONSE_431: &[u8] = b"HTTP/1.1 431 Request Header Fields Too Large\r\n\
+        Content-Length: 44\r\n\
+        Content-Type: text/html\r\n\
+        X-Content-Type-Options: nosniff\r\n\
+        Connection: close\r\n\r\n\
+        <h1>431 Request Header Fields Too Large</h1>";
+
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(1);
 
 pub struct Request {
+    // Unique within the process, to tell requests apart in the logs.
+    pub id: u64,
     pub version: String,
     pub host: String,
     pub method: String,
     pub url_path: String,
     pub fs_path: String,
-    pub is_gzip_allowed: bool,
-    pub is_deflate_allowed: bool,
+    pub accept_encoding: Option<String>,
+    pub accept: Option<String>,
+    pub referer: Option<String>,
+    pub user_agent: Option<String>,
     pub is_static: bool,
+    // Index into Config::static_mounts, set for static requests.
+    pub static_mount: usize,
+    pub content_length: usize,
+    // Set when the request can't be served as sent, e.g. for a
+    // Content-Length that isn't a number.
+    pub is_malformed: bool,
+    pub range: Option<String>,
+    pub if_range: Option<String>,
+    pub if_none_match: Option<String>,
+    pub if_modified_since: Option<String>,
+    pub body: Vec<u8>,
 }
 
 pub struct HttpResponse {
@@ -25,27 +72,89 @@ pub struct HttpResponse {
     pub content: String,
 }
 
-fn get_default_request() -> Request {
+pub(crate) fn get_default_request() -> Request {
     return Request {
+        id: NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed),
         version: "".to_string(),
         host: "".to_string(),
         method: "".to_string(),
         url_path: "".to_string(),
         fs_path: "".to_string(),
-        is_gzip_allowed: false,
-        is_deflate_allowed: false,
+        accept_encoding: None,
+        accept: None,
+        referer: None,
+        user_agent: None,
         is_static: false,
+        static_mount: 0,
+        content_length: 0,
+        is_malformed: false,
+        range: None,
+        if_range: None,
+        if_none_match: None,
+        if_modified_since: None,
+        body: Vec::new(),
+    }
+}
+
+// Maps a URL below the mount's prefix to a path below its dir. It works on
+// path components, so neither "..", a leading "//" nor an encoded slash can
+// leave the root. None for paths that must not be served at all.
+fn get_fs_path(requested_path: &str, mount: &StaticMount) -> Option<String> {
+    let requested_path = requested_path.split('?').next().unwrap_or("");
+    let stripped_path = requested_path.strip_prefix(mount.url.as_str())?;
+    let decoded_path = percent_decode(stripped_path)?;
+    let mut fs_path = PathBuf::from(&mount.dir);
+    for component in Path::new(&decoded_path).components() {
+        match component {
+            Component::Normal(name) => {
+                let name = name.to_str()?;
+                // .git, .env, .htpasswd and the like. .well-known is meant
+                // to be public.
+                if name.starts_with('.') && name != ".well-known" &&
+                   !mount.serve_dotfiles {
+                    return None;
+                }
+                fs_path.push(name);
+            },
+            Component::RootDir | Component::CurDir => (),
+            Component::ParentDir | Component::Prefix(_) => return None,
+        }
     }
+    return Some(fs_path.to_str()?.to_string());
 }
 
-fn get_fs_path(requested_path: &str, conf: &Config) -> String {
-    let static_dir = Path::new(&conf.static_dir);
-    let stripped_path = requested_path.replace(conf.static_url.as_str(), "");
-    return static_dir.join(stripped_path).clean().to_str().unwrap().to_string();
+fn is_path_safe(fs_path: &str, mount: &StaticMount) -> bool {
+    return Path::new(fs_path).starts_with(&mount.dir);
 }
 
-fn is_path_safe(fs_path: &str, conf: &Config) -> bool {
-    return fs_path.starts_with(&conf.static_dir);
+fn percent_decode(s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    if decoded.contains(&0) {
+        return None;
+    }
+    return String::from_utf8(decoded).ok();
+}
+
+// The value of the header if `line` is one, header names are matched
+// case-insensitively.
+fn header_value<'a>(line: &'a str, name: &str) -> Option<&'a str> {
+    let (line_name, value) = line.split_once(':')?;
+    if !line_name.eq_ignore_ascii_case(name) {
+        return None;
+    }
+    return Some(value.trim());
 }
 
 pub fn parse_request<'a>(request_str: &'a str,
@@ -62,38 +171,157 @@ pub fn parse_request<'a>(request_str: &'a str,
             if count > 2 {
                 request.version = split[2].to_string();
             }
-        } else if line.starts_with("Accept-Encoding: ") {
-            let split: Vec<&str> = line.split("Accept-Encoding: ").collect();
-            let count = line.split(" ").count();
-            if count > 1 {
-                let encodings: Vec<&str> = split[1].split(", ").collect();
-                for encoding in encodings {
-                    if encoding == "gzip" {
-                        request.is_gzip_allowed = true;
-                    } else if encoding == "deflate" {
-                        request.is_deflate_allowed = true;
-                    }
-                }
-            }
-        } else if line.starts_with("Host: ") {
-            let split: Vec<&str> = line.split(" ").collect();
-            let count = line.split(" ").count();
-            if count > 1 {
-                let host_split: Vec<&str> = split[1].split(":").collect();
-                request.host = host_split[0].to_string();
+        } else if let Some(value) = header_value(line, "Accept-Encoding") {
+            request.accept_encoding = Some(value.to_string());
+        } else if let Some(value) = header_value(line, "Accept") {
+            request.accept = Some(value.to_string());
+        } else if let Some(value) = header_value(line, "Referer") {
+            request.referer = Some(value.to_string());
+        } else if let Some(value) = header_value(line, "User-Agent") {
+            request.user_agent = Some(value.to_string());
+        } else if let Some(value) = header_value(line, "Content-Length") {
+            match value.parse() {
+                Ok(len) => request.content_length = len,
+                Err(_) => request.is_malformed = true,
             }
+        } else if let Some(value) = header_value(line, "Range") {
+            request.range = Some(value.to_string());
+        } else if let Some(value) = header_value(line, "If-Range") {
+            request.if_range = Some(value.to_string());
+        } else if let Some(value) = header_value(line, "If-None-Match") {
+            request.if_none_match = Some(value.to_string());
+        } else if let Some(value) = header_value(line, "If-Modified-Since") {
+            request.if_modified_since = Some(value.to_string());
+        } else if let Some(value) = header_value(line, "Host") {
+            request.host = value.split(':').next().unwrap_or("").to_string();
         }
     }
-    if request.url_path.starts_with(conf.static_url.as_str()) {
-        request.fs_path = get_fs_path(&request.url_path, conf);
-        if is_path_safe(&request.fs_path, conf) {
-            request.is_static = true;
-        }
+    // Mounts are sorted by the length of their prefix, so the first match is
+    // the longest one.
+    let mount = conf.static_mounts.iter()
+        .position(|m| request.url_path.starts_with(m.url.as_str()));
+    if let Some(i) = mount {
+        // Refused paths are left empty, so they get a 404 rather than being
+        // passed on to the app.
+        request.is_static = true;
+        request.static_mount = i;
+        let mount = &conf.static_mounts[i];
+        request.fs_path = match get_fs_path(&request.url_path, mount) {
+            Some(p) if is_path_safe(&p, mount) => p,
+            _ => String::new(),
+        };
     }
     return request;
 }
 
-pub fn return_404(mut stream: &TcpStream) {
-    stream.write(RESPONSE_404).unwrap();
-    stream.flush().unwrap();
+pub fn reason_phrase(code: u16) -> &'static str {
+    return match code {
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        206 => "Partial Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        303 => "See Other",
+        304 => "Not Modified",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        410 => "Gone",
+        413 => "Content Too Large",
+        414 => "URI Too Long",
+        416 => "Range Not Satisfiable",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "",
+    };
+}
+
+pub fn return_301(mut stream: &TcpStream, location: &str) {
+    let response = format!("HTTP/1.1 301 Moved Permanently\r\n\
+        Location: {}\r\n\
+        Content-Length: 0\r\n\
+        X-Content-Type-Options: nosniff\r\n\r\n", location);
+    let _ = stream.write_all(response.as_bytes());
+    let _ = stream.flush();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mount() -> StaticMount {
+        return StaticMount {
+            url: "/static/".to_string(),
+            dir: "/srv/www".to_string(),
+            gzip_min_size: 0,
+            gzip_max_size: 0,
+            gzip_file_types: Vec::new(),
+            precompressed_static: false,
+            cache_rules: Vec::new(),
+            fingerprinted_cache_control: String::new(),
+            index_files: Vec::new(),
+            autoindex: false,
+            autoindex_hidden: false,
+            fallback_file: String::new(),
+            follow_symlinks: "never".to_string(),
+            serve_dotfiles: false,
+        };
+    }
+
+    fn fs_path(url: &str) -> Option<String> {
+        return get_fs_path(url, &mount());
+    }
+
+    #[test]
+    fn percent_decoding() {
+        assert_eq!(percent_decode("a%20b%2Fc").as_deref(), Some("a b/c"));
+        assert_eq!(percent_decode("%C3%A9").as_deref(), Some("é"));
+        assert_eq!(percent_decode("100%"), None);
+        assert_eq!(percent_decode("%zz"), None);
+        assert_eq!(percent_decode("%FF"), None);
+        assert_eq!(percent_decode("a%00b"), None);
+    }
+
+    #[test]
+    fn maps_below_the_mount() {
+        assert_eq!(fs_path("/static/css/a.css").as_deref(), Some("/srv/www/css/a.css"));
+        assert_eq!(fs_path("/static/a%20b.txt?v=1").as_deref(), Some("/srv/www/a b.txt"));
+        assert_eq!(fs_path("/static/./a//b").as_deref(), Some("/srv/www/a/b"));
+        assert_eq!(fs_path("/other/a.css"), None);
+    }
+
+    #[test]
+    fn cannot_leave_the_root() {
+        for url in ["/static/../etc/passwd", "/static/a/../../etc/passwd",
+                    "/static/%2e%2e/etc/passwd", "/static/%2E%2E%2Fetc%2Fpasswd",
+                    "/static/a%00.txt"] {
+            assert_eq!(fs_path(url), None, "{}", url);
+        }
+        // An absolute path after decoding still stays below dir.
+        assert_eq!(fs_path("/static/%2Fetc/passwd").as_deref(),
+                   Some("/srv/www/etc/passwd"));
+    }
+
+    #[test]
+    fn dotfiles() {
+        assert_eq!(fs_path("/static/.env"), None);
+        assert_eq!(fs_path("/static/a/.git/config"), None);
+        assert_eq!(fs_path("/static/.well-known/x").as_deref(),
+                   Some("/srv/www/.well-known/x"));
+        let mut mount = mount();
+        mount.serve_dotfiles = true;
+        assert_eq!(get_fs_path("/static/.env", &mount).as_deref(), Some("/srv/www/.env"));
+    }
 }
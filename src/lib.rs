@@ -1,18 +1,38 @@
+#![allow(clippy::needless_return)]
+
+use std::any::Any;
+use std::cell::Cell;
+use std::panic;
 use std::thread;
-use std::sync::mpsc;
 use std::sync::Arc;
-use std::sync::Mutex;
+use std::sync::{Mutex, MutexGuard};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender};
+use log::{info, error};
 
 pub mod server;
+pub mod reactor;
+#[cfg(feature = "async")]
+pub mod async_server;
 pub mod config_parser;
 pub mod logger;
 pub mod mime;
 pub mod http;
+pub mod error_pages;
+pub mod access_log;
+pub mod encoding;
+pub mod range;
+pub mod static_files;
+pub mod autoindex;
+pub mod file_cache;
 pub mod default_app;
 
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
 pub struct ThreadPool {
-	workers: Vec<Worker>,
-	sender: mpsc::SyncSender<Message>,
+	sender: Sender<Message>,
+	shared: Arc<Shared>,
 }
 trait FnBox {
 	fn call_box(self: Box<Self>);
@@ -26,6 +46,8 @@ type Job = Box<dyn FnBox + Send + 'static>;
 
 enum Message {
 	NewJob(Job),
+	// Wakes an idle worker up to check whether it should leave.
+	Retire,
 	Terminate,
 }
 struct Worker {
@@ -33,75 +55,307 @@ struct Worker {
 	thread: Option<thread::JoinHandle<()>>,
 }
 
+// Everything a worker needs to run and, if it dies, to replace itself.
+// Workers pull jobs from a multi-consumer channel and the bookkeeping is
+// kept in atomics, so dispatching a job never takes a lock.
+//
+// `live` counts running workers, `idle` the ones waiting for a job and
+// `to_retire` how many were asked to leave by kill_some_workers. A worker
+// only updates `idle` when it finds the queue empty or picks a job up after
+// waiting, so jobs taken back to back don't touch it.
+struct Shared {
+	receiver: Receiver<Message>,
+	min_threads: AtomicUsize,
+	max_threads: AtomicUsize,
+	idle_timeout: Duration,
+	live: AtomicUsize,
+	idle: AtomicUsize,
+	to_retire: AtomicUsize,
+	next_id: AtomicUsize,
+	shutting_down: AtomicBool,
+	workers: Mutex<Vec<Worker>>,
+}
+
+impl Shared {
+	// A worker may only leave while the pool stays at or above min_threads.
+	fn try_retire(&self) -> bool {
+		let min_threads = self.min_threads.load(Ordering::SeqCst);
+		let r = self.live.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |live| {
+			if live > min_threads { Some(live - 1) } else { None }
+		});
+		let live = match r {
+			Ok(live) => live - 1,
+			Err(_) => return false,
+		};
+		// execute doesn't spawn while it sees this worker idle, so if it was
+		// the last one, a job queued in the meantime would be left without
+		// a worker.
+		if live == 0 && !self.receiver.is_empty() {
+			self.live.fetch_add(1, Ordering::SeqCst);
+			return false;
+		}
+		return true;
+	}
+
+	// Checked by workers between jobs, so the pool shrinks to max_threads
+	// and honours kill_some_workers even when no Retire message got through.
+	fn should_leave(&self) -> bool {
+		let max_threads = self.max_threads.load(Ordering::SeqCst);
+		let r = self.live.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |live| {
+			if live > max_threads { Some(live - 1) } else { None }
+		});
+		if r.is_ok() {
+			return true;
+		}
+		let asked = self.to_retire.fetch_update(Ordering::SeqCst, Ordering::SeqCst,
+		                                        |n| n.checked_sub(1));
+		if asked.is_err() {
+			return false;
+		}
+		if self.try_retire() {
+			return true;
+		}
+		// Already down to min_threads, the remaining requests can't be met.
+		self.to_retire.store(0, Ordering::SeqCst);
+		return false;
+	}
+
+	// Reserves a slot for a new worker unless the pool is already full.
+	fn try_grow(&self, limit: usize) -> bool {
+		return self.live.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |live| {
+			if live < limit { Some(live + 1) } else { None }
+		}).is_ok();
+	}
+}
+
 
 impl ThreadPool {
+	/// Creates a pool with a fixed number of workers.
 	pub fn new(thread_count: usize) -> ThreadPool {
+		return ThreadPool::with_limits(thread_count, thread_count,
+		                               DEFAULT_IDLE_TIMEOUT);
+	}
 
-        if thread_count <= 0 {
-            panic!("Number of threads should be bigger than 0.");
-        }
+	/// Creates a pool that starts with `min_threads` workers, spawns more
+	/// under load up to `max_threads` and retires workers that have been
+	/// idle for longer than `idle_timeout`.
+	pub fn with_limits(min_threads: usize, max_threads: usize,
+	                   idle_timeout: Duration) -> ThreadPool {
+		check_limits(min_threads, max_threads);
 
-		let (sender, receiver) = mpsc::sync_channel(1000);
-		let receiver = Arc::new(Mutex::new(receiver));
-		let mut workers = Vec::with_capacity(thread_count);
-		for id in 0..thread_count {
-			workers.push(Worker::new(id, Arc::clone(&receiver)));
+		let (sender, receiver) = crossbeam_channel::bounded(1000);
+		let shared = Arc::new(Shared {
+			receiver,
+			min_threads: AtomicUsize::new(min_threads),
+			max_threads: AtomicUsize::new(max_threads),
+			idle_timeout,
+			live: AtomicUsize::new(0),
+			idle: AtomicUsize::new(0),
+			to_retire: AtomicUsize::new(0),
+			next_id: AtomicUsize::new(0),
+			shutting_down: AtomicBool::new(false),
+			workers: Mutex::new(Vec::with_capacity(max_threads)),
+		});
+		while shared.try_grow(min_threads) {
+			spawn_worker(&shared);
 		}
-		ThreadPool {
-			workers,
+		return ThreadPool {
 			sender,
-		}
+			shared,
+		};
 	}
 
 	pub fn execute<F>(&self, f: F)
 		where
 			F: FnOnce() + Send + 'static
 	{
-        //println!("execute()");
 		let job = Box::new(f);
-		//self.sender.try_send(Message::NewJob(job)).unwrap();
-		let r = self.sender.try_send(Message::NewJob(job));
-        if r.is_err() {
-            println!("R: {:?}", r);
-        }
+		let shared = &self.shared;
+		let idle = shared.idle.load(Ordering::SeqCst);
+		if (idle == 0 || self.sender.len() >= idle) &&
+		   shared.try_grow(shared.max_threads.load(Ordering::SeqCst)) {
+			spawn_worker(shared);
+		}
+		// The job is dropped, and with it the connection, when the queue is
+		// full.
+		if let Err(e) = self.sender.try_send(Message::NewJob(job)) {
+			error!("Failed to queue a job: {}", e);
+			return;
+		}
+		// With min_threads at 0 the last worker may have retired since the
+		// check above.
+		if shared.live.load(Ordering::SeqCst) == 0 &&
+		   shared.try_grow(shared.max_threads.load(Ordering::SeqCst)) {
+			spawn_worker(shared);
+		}
+	}
 
+	/// Changes the pool bounds at runtime. Missing workers are spawned right
+	/// away, surplus ones retire as soon as they finish their current job.
+	pub fn resize(&self, min_threads: usize, max_threads: usize) {
+		check_limits(min_threads, max_threads);
+		let shared = &self.shared;
+		shared.min_threads.store(min_threads, Ordering::SeqCst);
+		shared.max_threads.store(max_threads, Ordering::SeqCst);
+		while shared.try_grow(min_threads) {
+			spawn_worker(shared);
+		}
+		let surplus = shared.live.load(Ordering::SeqCst)
+			.saturating_sub(max_threads);
+		self.wake_idle(surplus);
+	}
+
+	/// Asks up to `count` workers to retire, never going below min_threads.
+	pub fn kill_some_workers(&self, count: usize) {
+		let shared = &self.shared;
+		let count = count.min(shared.live.load(Ordering::SeqCst)
+			.saturating_sub(shared.min_threads.load(Ordering::SeqCst)));
+		info!("Killing workers: {}", count);
+		shared.to_retire.fetch_add(count, Ordering::SeqCst);
+		self.wake_idle(count);
+	}
+
+	// Busy workers check should_leave after their job anyway, so it's fine
+	// when the channel is too full for some of the messages.
+	fn wake_idle(&self, count: usize) {
+		for _ in 0..count {
+			if self.sender.try_send(Message::Retire).is_err() {
+				break;
+			}
+		}
+	}
+
+	/// Number of currently running workers.
+	pub fn size(&self) -> usize {
+		return self.shared.live.load(Ordering::SeqCst);
 	}
+}
+
+// The caller must have already counted the new worker in `live`.
+fn spawn_worker(shared: &Arc<Shared>) {
+	let id = shared.next_id.fetch_add(1, Ordering::SeqCst);
+	shared.idle.fetch_add(1, Ordering::SeqCst);
+	let worker = Worker::new(id, Arc::clone(shared));
+	let mut workers = lock(&shared.workers);
+	workers.retain(|w| !w.is_finished());
+	workers.push(worker);
+}
 
-    pub fn kill_some_workers(count: usize) {
-        println!("Killing workers: {}", count);
-    }
+// A panicking job must not take the whole pool down with it, so poisoned
+// locks are simply taken over: the data they guard stays consistent because
+// jobs never run while holding them.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+	return mutex.lock().unwrap_or_else(|e| e.into_inner());
+}
+
+pub(crate) fn panic_message(payload: &(dyn Any + Send)) -> &str {
+	if let Some(s) = payload.downcast_ref::<&str>() {
+		return s;
+	}
+	if let Some(s) = payload.downcast_ref::<String>() {
+		return s.as_str();
+	}
+	return "Box<dyn Any>";
+}
+
+// A quoted JSON string.
+pub(crate) fn escape_json(s: &str) -> String {
+	let mut out = String::with_capacity(s.len() + 2);
+	out.push('"');
+	for c in s.chars() {
+		match c {
+			'"' => out.push_str("\\\""),
+			'\\' => out.push_str("\\\\"),
+			'\n' => out.push_str("\\n"),
+			'\r' => out.push_str("\\r"),
+			'\t' => out.push_str("\\t"),
+			c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+			c => out.push(c),
+		}
+	}
+	out.push('"');
+	return out;
+}
+
+fn check_limits(min_threads: usize, max_threads: usize) {
+	if max_threads == 0 {
+		panic!("Number of threads should be bigger than 0.");
+	}
+	if min_threads > max_threads {
+		panic!("min_threads ({}) should not be bigger than max_threads ({}).",
+		       min_threads, max_threads);
+	}
 }
 
 impl Drop for ThreadPool {
 	fn drop(&mut self) {
-		println!("Sending terminate to all workers");
-		for _ in &mut self.workers {
+		info!("Sending terminate to all workers");
+		self.shared.shutting_down.store(true, Ordering::SeqCst);
+		for _ in 0..self.shared.live.load(Ordering::SeqCst) {
 			self.sender.send(Message::Terminate).unwrap();
 		}
-		println!("Shutting down all workers");
-		for worker in &mut self.workers {
-			println!("Shutting down worker {}", worker.id);
+		info!("Shutting down all workers");
+		let mut workers = std::mem::take(&mut *lock(&self.shared.workers));
+		for worker in &mut workers {
+			info!("Shutting down worker {}", worker.id);
 
 			if let Some(thread) = worker.thread.take() {
-				thread.join().unwrap();
+				let _ = thread.join();
 			}
 		}
 	}
 }
 
 impl Worker {
-	fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Message>>>) -> Worker {
+	fn new(id: usize, shared: Arc<Shared>) -> Worker {
 		let thread = thread::spawn(move || {
+			let sentinel = Sentinel { id, shared, is_idle: Cell::new(true) };
+			let shared = &sentinel.shared;
+			let is_idle = &sentinel.is_idle;
 			loop {
-                //println!("Loop {}", id);
-				let message = receiver.lock().unwrap().recv().unwrap();
+				// recv_timeout reads the clock on every call, which is only
+				// worth it when the queue is empty.
+				let message = match shared.receiver.try_recv() {
+					Ok(message) => Ok(message),
+					Err(_) => {
+						if !is_idle.replace(true) {
+							shared.idle.fetch_add(1, Ordering::SeqCst);
+						}
+						shared.receiver.recv_timeout(shared.idle_timeout)
+					},
+				};
 				match message {
-					Message::NewJob(job) => {
-						//println!("Worker {} got a job; executing.", id);
-						job.call_box();
+					Ok(Message::NewJob(job)) => {
+						if is_idle.replace(false) {
+							shared.idle.fetch_sub(1, Ordering::SeqCst);
+						}
+						let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+							job.call_box();
+						}));
+						if let Err(e) = result {
+							error!("Worker {} recovered from a panicked job: {}",
+							       id, panic_message(&*e));
+						}
+						if shared.should_leave() {
+							info!("Worker {} retired", id);
+							break;
+						}
+					},
+					Ok(Message::Retire) => {
+						if shared.should_leave() {
+							info!("Worker {} retired", id);
+							break;
+						}
 					},
-					Message::Terminate => {
-						println!("Worker {} was told to terminate", id);
+					Err(RecvTimeoutError::Timeout) => {
+						if shared.try_retire() {
+							info!("Worker {} retired after being idle", id);
+							break;
+						}
+					},
+					Ok(Message::Terminate) |
+					Err(RecvTimeoutError::Disconnected) => {
+						info!("Worker {} was told to terminate", id);
 						break;
 					},
 				}
@@ -114,4 +368,179 @@ impl Worker {
 		}
 	}
 
+	fn is_finished(&self) -> bool {
+		return match &self.thread {
+			Some(thread) => thread.is_finished(),
+			None => true,
+		};
+	}
+}
+
+// Lives on a worker's stack and takes the worker out of `idle` however it
+// stops. If the worker thread unwinds anyway, a fresh worker takes its place
+// so the pool keeps its size. Jobs run under catch_unwind, so this is for
+// panics in the worker loop itself, e.g. the logger's println! failing
+// because stdout is gone.
+struct Sentinel {
+	id: usize,
+	shared: Arc<Shared>,
+	is_idle: Cell<bool>,
+}
+
+impl Drop for Sentinel {
+	fn drop(&mut self) {
+		let shared = &self.shared;
+		if self.is_idle.get() {
+			shared.idle.fetch_sub(1, Ordering::SeqCst);
+		}
+		if !thread::panicking() {
+			return;
+		}
+		if shared.shutting_down.load(Ordering::SeqCst) {
+			shared.live.fetch_sub(1, Ordering::SeqCst);
+			return;
+		}
+		// The dead worker's slot in `live` is handed over to its replacement.
+		error!("Worker {} died, spawning a replacement", self.id);
+		spawn_worker(shared);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::time::Instant;
+	use crossbeam_channel::unbounded;
+
+	const IDLE: Duration = Duration::from_millis(50);
+	const LONG: Duration = Duration::from_secs(60);
+
+	fn wait_for<F: Fn() -> bool>(f: F) -> bool {
+		let deadline = Instant::now() + Duration::from_secs(5);
+		while Instant::now() < deadline {
+			if f() {
+				return true;
+			}
+			thread::sleep(Duration::from_millis(5));
+		}
+		return f();
+	}
+
+	// Queues `count` jobs that block until the returned sender is dropped.
+	fn block(pool: &ThreadPool, count: usize) -> Sender<()> {
+		let (release, gate) = unbounded::<()>();
+		for _ in 0..count {
+			let gate = gate.clone();
+			pool.execute(move || {
+				let _ = gate.recv();
+			});
+		}
+		return release;
+	}
+
+	fn run(pool: &ThreadPool) -> bool {
+		let (done, finished) = unbounded();
+		pool.execute(move || {
+			let _ = done.send(());
+		});
+		return finished.recv_timeout(Duration::from_secs(5)).is_ok();
+	}
+
+	#[test]
+	fn grows_under_load_up_to_max() {
+		let pool = ThreadPool::with_limits(1, 4, LONG);
+		assert_eq!(pool.size(), 1);
+		let release = block(&pool, 8);
+		assert!(wait_for(|| pool.size() == 4));
+		thread::sleep(Duration::from_millis(50));
+		assert_eq!(pool.size(), 4);
+		drop(release);
+		assert!(run(&pool));
+	}
+
+	#[test]
+	fn idle_workers_retire_down_to_min() {
+		let pool = ThreadPool::with_limits(2, 4, IDLE);
+		drop(block(&pool, 8));
+		assert!(wait_for(|| pool.size() == 2));
+		thread::sleep(IDLE * 4);
+		assert_eq!(pool.size(), 2);
+		assert!(run(&pool));
+	}
+
+	#[test]
+	fn resize() {
+		let pool = ThreadPool::with_limits(2, 2, LONG);
+		pool.resize(4, 6);
+		assert_eq!(pool.size(), 4);
+		pool.resize(1, 2);
+		assert!(wait_for(|| pool.size() == 2));
+		assert!(run(&pool));
+	}
+
+	#[test]
+	fn resize_waits_for_busy_workers() {
+		let pool = ThreadPool::with_limits(3, 3, LONG);
+		let release = block(&pool, 3);
+		assert!(wait_for(|| pool.shared.idle.load(Ordering::SeqCst) == 0));
+		pool.resize(1, 1);
+		assert_eq!(pool.size(), 3);
+		drop(release);
+		assert!(wait_for(|| pool.size() == 1));
+	}
+
+	#[test]
+	fn kill_some_workers_keeps_min_threads() {
+		let pool = ThreadPool::with_limits(2, 5, LONG);
+		pool.resize(5, 5);
+		pool.resize(2, 5);
+		assert_eq!(pool.size(), 5);
+		pool.kill_some_workers(1);
+		assert!(wait_for(|| pool.size() == 4));
+		pool.kill_some_workers(10);
+		assert!(wait_for(|| pool.size() == 2));
+		thread::sleep(Duration::from_millis(50));
+		assert_eq!(pool.size(), 2);
+		assert!(run(&pool));
+	}
+
+	#[test]
+	fn no_workers_with_min_zero() {
+		let pool = ThreadPool::with_limits(0, 2, IDLE);
+		assert_eq!(pool.size(), 0);
+		assert!(run(&pool));
+		assert!(wait_for(|| pool.size() == 0));
+		assert!(run(&pool));
+	}
+
+	#[test]
+	fn panicking_jobs_dont_kill_workers() {
+		let pool = ThreadPool::with_limits(1, 1, LONG);
+		pool.execute(|| panic!("job panicked on purpose"));
+		assert!(run(&pool));
+		assert_eq!(pool.size(), 1);
+	}
+
+	#[test]
+	fn dead_workers_are_replaced() {
+		let pool = ThreadPool::with_limits(1, 1, LONG);
+		// Stands in for a worker whose loop panics outside of a job.
+		let shared = Arc::clone(&pool.shared);
+		shared.live.fetch_add(1, Ordering::SeqCst);
+		shared.idle.fetch_add(1, Ordering::SeqCst);
+		let dying = thread::spawn(move || {
+			let _sentinel = Sentinel { id: 99, shared, is_idle: Cell::new(true) };
+			panic!("worker loop panicked on purpose");
+		});
+		assert!(dying.join().is_err());
+		assert_eq!(pool.size(), 2);
+		assert_eq!(pool.shared.next_id.load(Ordering::SeqCst), 2);
+		assert!(run(&pool));
+	}
+
+	#[test]
+	#[should_panic(expected = "should be bigger than 0")]
+	fn max_threads_must_not_be_zero() {
+		ThreadPool::with_limits(0, 0, IDLE);
+	}
 }
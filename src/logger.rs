@@ -1,6 +1,38 @@
-use log::{Record, Level, Metadata};
+use std::cell::RefCell;
+#[cfg(feature = "async")]
+use std::future::Future;
+#[cfg(feature = "async")]
+use std::pin::Pin;
+#[cfg(feature = "async")]
+use std::task::{self, Poll};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use log::{Record, Level, Metadata, info};
+use crate::access_log::utc_datetime;
+use crate::escape_json;
+use crate::http::Request;
 
 pub static SIMPLE_LOGGER: SimpleLogger = SimpleLogger;
+pub static JSON_LOGGER: JsonLogger = JsonLogger;
+
+/// The logger the servers install. It writes text until the config asks for
+/// JSON, so errors in the config itself are still reported.
+pub static LOGGER: Logger = Logger;
+
+static IS_JSON: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    // The request the current thread is working on, if any.
+    static CONTEXT: RefCell<Option<RequestContext>> = const { RefCell::new(None) };
+}
+
+struct RequestContext {
+    id: u64,
+    method: String,
+    path: String,
+    status: Option<u16>,
+    duration: Option<Duration>,
+}
 
 pub struct SimpleLogger;
 
@@ -17,3 +49,154 @@ impl log::Log for SimpleLogger {
 
     fn flush(&self) {}
 }
+
+/// One JSON object per line, with the request being handled, if any.
+pub struct JsonLogger;
+
+impl log::Log for JsonLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= Level::Info
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let now = SystemTime::now();
+        let millis = now.duration_since(UNIX_EPOCH)
+            .map(|d| d.subsec_millis()).unwrap_or(0);
+        let mut line = format!(
+            "{{\"timestamp\":\"{}.{:03}Z\",\"level\":\"{}\",\"target\":{},\"message\":{}",
+            utc_datetime(now), millis, record.level(), escape_json(record.target()),
+            escape_json(&record.args().to_string()));
+        CONTEXT.with(|context| {
+            if let Some(c) = context.borrow().as_ref() {
+                line.push_str(&format!(",\"request_id\":{},\"method\":{},\"path\":{}",
+                                       c.id, escape_json(&c.method),
+                                       escape_json(&c.path)));
+                if let Some(status) = c.status {
+                    line.push_str(&format!(",\"status\":{}", status));
+                }
+                if let Some(duration) = c.duration {
+                    line.push_str(&format!(",\"duration_ms\":{:.3}",
+                                           duration.as_secs_f64() * 1000.0));
+                }
+            }
+        });
+        line.push('}');
+        println!("{}", line);
+    }
+
+    fn flush(&self) {}
+}
+
+pub struct Logger;
+
+impl Logger {
+    /// Switches between SIMPLE_LOGGER and JSON_LOGGER.
+    pub fn set_json(&self, is_json: bool) {
+        IS_JSON.store(is_json, Ordering::Relaxed);
+    }
+
+    fn inner(&self) -> &'static dyn log::Log {
+        if IS_JSON.load(Ordering::Relaxed) {
+            return &JSON_LOGGER;
+        }
+        return &SIMPLE_LOGGER;
+    }
+}
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner().enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        self.inner().log(record)
+    }
+
+    fn flush(&self) {
+        self.inner().flush()
+    }
+}
+
+/// Runs `f` with log records on this thread tagged with the request.
+pub(crate) fn with_request<T, F: FnOnce() -> T>(request: &Request, f: F) -> T {
+    return with_context(context_of(request), f);
+}
+
+/// Wraps `future` so log records are tagged with the request whenever it
+/// runs, on whichever thread polls it.
+#[cfg(feature = "async")]
+pub(crate) fn in_request<F: Future + Unpin>(request: &Request,
+                                            future: F) -> InRequest<F> {
+    return InRequest { future, context: Some(context_of(request)) };
+}
+
+#[cfg(feature = "async")]
+pub(crate) struct InRequest<F> {
+    future: F,
+    // Only None while the future is being polled.
+    context: Option<RequestContext>,
+}
+
+#[cfg(feature = "async")]
+impl<F: Future + Unpin> Future for InRequest<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut task::Context) -> Poll<F::Output> {
+        // Takes the context back and restores the previous one even if the
+        // future panics.
+        struct Restore<'a> {
+            previous: Option<RequestContext>,
+            slot: &'a mut Option<RequestContext>,
+        }
+        impl Drop for Restore<'_> {
+            fn drop(&mut self) {
+                let previous = self.previous.take();
+                *self.slot = CONTEXT.with(|c| c.replace(previous));
+            }
+        }
+        let this = &mut *self;
+        let previous = CONTEXT.with(|c| c.replace(this.context.take()));
+        let _restore = Restore { previous, slot: &mut this.context };
+        return Pin::new(&mut this.future).poll(cx);
+    }
+}
+
+/// Logs that the response to the request has been sent.
+pub(crate) fn log_completed(request: &Request, status: u16, started: Instant) {
+    let context = RequestContext {
+        status: Some(status),
+        duration: Some(started.elapsed()),
+        ..context_of(request)
+    };
+    with_context(context, || {
+        info!(target: "rase::server", "{} {} {} {}", request.host, request.method,
+              request.url_path, status);
+    });
+}
+
+fn context_of(request: &Request) -> RequestContext {
+    return RequestContext {
+        id: request.id,
+        method: request.method.clone(),
+        path: request.url_path.clone(),
+        status: None,
+        duration: None,
+    };
+}
+
+fn with_context<T, F: FnOnce() -> T>(context: RequestContext, f: F) -> T {
+    // Puts the previous context back even if `f` panics.
+    struct Restore(Option<RequestContext>);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            let previous = self.0.take();
+            CONTEXT.with(|c| *c.borrow_mut() = previous);
+        }
+    }
+    let previous = CONTEXT.with(|c| c.borrow_mut().replace(context));
+    let _restore = Restore(previous);
+    return f();
+}
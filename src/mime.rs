@@ -4,15 +4,124 @@
 //
 // Rase uses only fairly popular file extensions.
 
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io;
+use std::io::prelude::*;
 use std::path::Path;
+use crate::config_parser::Config;
 
+// How much of a file is looked at to guess its type.
+const SNIFF_LEN: usize = 512;
 
-pub fn get_mimetype (fname: &str) -> Option<&str> {
-    let r = Path::new(&fname).extension();
-    if !r.is_some() {
+/// The Content-Type header value for a file: [mime_types] from the config,
+/// then the builtin table, then the file's magic bytes if sniff_mime_types
+/// is set, then default_mime_type. Text types are declared as UTF-8.
+pub fn get_content_type(fname: &str, conf: &Config) -> Option<String> {
+    let mime_type = match lookup(fname, conf) {
+        Some(m) => m,
+        None => match sniff_file(fname, conf) {
+            Some(m) => m,
+            None if !conf.default_mime_type.is_empty() => &conf.default_mime_type,
+            None => return None,
+        },
+    };
+    return Some(with_charset(mime_type));
+}
+
+/// The type for the file's extension, without falling back to the default.
+pub fn lookup<'a>(fname: &str, conf: &'a Config) -> Option<&'a str> {
+    let ext = Path::new(&fname).extension()?.to_str()?.to_ascii_lowercase();
+    if let Some(m) = conf.mime_types.get(&ext) {
+        return Some(m);
+    }
+    return get_mimetype(fname);
+}
+
+/// Reads an Apache-style mime.types file ("type ext ext ...", one per line).
+/// The nginx variant with a `types { ... }` block and trailing semicolons is
+/// understood as well.
+pub fn load_mime_types(path: &str) -> io::Result<HashMap<String, String>> {
+    let contents = fs::read_to_string(path)?;
+    let mut mime_types = HashMap::new();
+    for line in contents.lines() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        let line = line.trim_end_matches(';');
+        let mut words = line.split_whitespace();
+        let mime_type = match words.next() {
+            Some(m) if m.contains('/') => m,
+            // Empty lines and the nginx "types {" / "}" lines.
+            _ => continue,
+        };
+        for ext in words {
+            mime_types.insert(ext.to_ascii_lowercase(), mime_type.to_string());
+        }
+    }
+    return Ok(mime_types);
+}
+
+fn sniff_file(fname: &str, conf: &Config) -> Option<&'static str> {
+    if !conf.sniff_mime_types {
         return None;
     }
-    let m = match r.unwrap().to_str().unwrap().to_ascii_lowercase().as_str() {
+    let mut head = Vec::with_capacity(SNIFF_LEN);
+    File::open(fname).ok()?.take(SNIFF_LEN as u64).read_to_end(&mut head).ok()?;
+    return sniff(&head);
+}
+
+/// Guesses the type from the first bytes of a file.
+pub fn sniff(head: &[u8]) -> Option<&'static str> {
+    let signatures: &[(&[u8], &str)] = &[
+        (b"\x89PNG\r\n\x1a\n", "image/png"),
+        (b"\xff\xd8\xff", "image/jpeg"),
+        (b"GIF87a", "image/gif"),
+        (b"GIF89a", "image/gif"),
+        (b"%PDF-", "application/pdf"),
+        (b"PK\x03\x04", "application/zip"),
+        (b"PK\x05\x06", "application/zip"),
+        (b"\x1f\x8b", "application/gzip"),
+        (b"\0asm", "application/wasm"),
+    ];
+    for (magic, mime_type) in signatures {
+        if head.starts_with(magic) {
+            return Some(mime_type);
+        }
+    }
+    if head.len() >= 12 && &head[..4] == b"RIFF" && &head[8..12] == b"WEBP" {
+        return Some("image/webp");
+    }
+    if is_text(head) {
+        return Some("text/plain");
+    }
+    return None;
+}
+
+// UTF-8 without control characters that don't occur in text files. The
+// head may end in the middle of a character.
+fn is_text(head: &[u8]) -> bool {
+    if head.is_empty() {
+        return false;
+    }
+    let text = match std::str::from_utf8(head) {
+        Ok(t) => t,
+        Err(e) if e.error_len().is_none() && e.valid_up_to() > 0 => {
+            std::str::from_utf8(&head[..e.valid_up_to()]).unwrap_or("")
+        },
+        Err(_) => return false,
+    };
+    return text.chars().all(|c| !c.is_control() || "\t\n\r\x0c\x1b".contains(c));
+}
+
+fn with_charset(mime_type: &str) -> String {
+    if mime_type.starts_with("text/") && !mime_type.contains("charset=") {
+        return format!("{}; charset=utf-8", mime_type);
+    }
+    return mime_type.to_string();
+}
+
+pub fn get_mimetype (fname: &str) -> Option<&'static str> {
+    let r = Path::new(&fname).extension()?;
+    let m = match r.to_str()?.to_ascii_lowercase().as_str() {
         // application
         "7z" => "application/x-7z-compressed",
         "bin" => "application/octet-stream",
@@ -24,6 +133,7 @@ pub fn get_mimetype (fname: &str) -> Option<&str> {
         "gz" => "application/gzip",
         "gzip" => "application/gzip",
         "json" => "application/json",
+        "map" => "application/json",
         "odp" => "application/vnd.oasis.opendocument.presentation",
         "ods" => "application/vnd.oasis.opendocument.spreadsheet",
         "odt" => "application/vnd.oasis.opendocument.text",
@@ -33,15 +143,20 @@ pub fn get_mimetype (fname: &str) -> Option<&str> {
         "sh" => "application/x-sh",
         "sql" => "application/sql",
         "tar" => "application/x-tar",
+        "wasm" => "application/wasm",
+        "webmanifest" => "application/manifest+json",
         "xls" => "application/vnd.ms-excel",
         "zip" => "application/zip",
         // audio
         "aac" => "audio/aac",
+        "m4a" => "audio/mp4",
+        "mp4a" => "audio/mp4",
         "oga" => "audio/ogg",
         "ogg" => "audio/ogg",
         "opus" => "audio/opus",
         "mp3" => "audio/mpeg",
         // image
+        "avif" => "image/avif",
         "gif" => "image/gif",
         "ico" => "image/vnd.microsoft.icon",
         "jpg" => "image/jpeg",
@@ -68,10 +183,10 @@ pub fn get_mimetype (fname: &str) -> Option<&str> {
         "3gp" => "video/3gpp",
         "3gpp" => "video/3gpp",
         "avi" => "video/x-msvideo",
-        "H261" => "video/H261",
-        "H263" => "video/H263",
-        "H264" => "video/H264",
-        "H265" => "video/H265",
+        "h261" => "video/H261",
+        "h263" => "video/H263",
+        "h264" => "video/H264",
+        "h265" => "video/H265",
         "mp4" => "video/mp4",
         "mpeg" => "video/mpeg",
         "mpg" => "video/mpeg",
@@ -82,3 +197,49 @@ pub fn get_mimetype (fname: &str) -> Option<&str> {
     };
     return Some(m);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn load(name: &str, contents: &str) -> HashMap<String, String> {
+        let path = env::temp_dir().join(format!("rase-{}-{}", std::process::id(), name));
+        fs::write(&path, contents).unwrap();
+        let mime_types = load_mime_types(path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+        return mime_types.unwrap();
+    }
+
+    fn get<'a>(mime_types: &'a HashMap<String, String>, ext: &str) -> Option<&'a str> {
+        return mime_types.get(ext).map(String::as_str);
+    }
+
+    #[test]
+    fn apache_format() {
+        let mime_types = load("apache", "# MIME type\tExtensions\n\
+            text/css\t\t\t\t\tcss\n\
+            application/x-thing\t\tthg THG2  # trailing comment\n\
+            \n\
+            application/x-no-extensions\n");
+        assert_eq!(get(&mime_types, "css"), Some("text/css"));
+        assert_eq!(get(&mime_types, "thg"), Some("application/x-thing"));
+        assert_eq!(get(&mime_types, "thg2"), Some("application/x-thing"));
+        assert_eq!(mime_types.len(), 3);
+    }
+
+    #[test]
+    fn nginx_format() {
+        let mime_types = load("nginx", "\ntypes {\n    text/html  html htm shtml;\n    \
+            image/avif avif;\n}\n");
+        assert_eq!(get(&mime_types, "htm"), Some("text/html"));
+        assert_eq!(get(&mime_types, "shtml"), Some("text/html"));
+        assert_eq!(get(&mime_types, "avif"), Some("image/avif"));
+        assert_eq!(mime_types.len(), 4);
+    }
+
+    #[test]
+    fn missing_file() {
+        assert!(load_mime_types("/nonexistent/mime.types").is_err());
+    }
+}
@@ -0,0 +1,127 @@
+// Parsing of `Range: bytes=...` request headers (RFC 7233).
+
+// Serving more ranges than this in one multipart response isn't worth it,
+// such requests simply get the whole file.
+const MAX_RANGES: usize = 16;
+
+pub enum RangeSet {
+    /// No usable Range header, the whole file should be sent.
+    Full,
+    /// None of the requested ranges overlaps the file: 416.
+    Unsatisfiable,
+    /// Inclusive (first, last) byte positions, in the requested order.
+    Ranges(Vec<(u64, u64)>),
+}
+
+pub fn parse(header: &str, size: u64) -> RangeSet {
+    let specs = match header.trim().strip_prefix("bytes=") {
+        Some(s) => s,
+        None => return RangeSet::Full,
+    };
+    let mut ranges = Vec::new();
+    let mut count = 0;
+    for spec in specs.split(',') {
+        let spec = spec.trim();
+        if spec.is_empty() {
+            continue;
+        }
+        count += 1;
+        if count > MAX_RANGES {
+            return RangeSet::Full;
+        }
+        match parse_spec(spec, size) {
+            Err(()) => return RangeSet::Full,
+            Ok(None) => (),
+            Ok(Some(range)) => ranges.push(range),
+        }
+    }
+    if count == 0 {
+        return RangeSet::Full;
+    }
+    if ranges.is_empty() {
+        return RangeSet::Unsatisfiable;
+    }
+    return RangeSet::Ranges(ranges);
+}
+
+// Err for syntax errors, None for a valid range that lies outside the file.
+fn parse_spec(spec: &str, size: u64) -> Result<Option<(u64, u64)>, ()> {
+    let (first, last) = match spec.find('-') {
+        Some(p) => (spec[..p].trim(), spec[p + 1..].trim()),
+        None => return Err(()),
+    };
+    if first.is_empty() {
+        // Suffix range: the last N bytes.
+        let len: u64 = last.parse().map_err(|_| ())?;
+        if len == 0 || size == 0 {
+            return Ok(None);
+        }
+        return Ok(Some((size.saturating_sub(len), size - 1)));
+    }
+    let first: u64 = first.parse().map_err(|_| ())?;
+    let last: u64 = if last.is_empty() {
+        u64::MAX
+    } else {
+        last.parse().map_err(|_| ())?
+    };
+    if last < first {
+        return Err(());
+    }
+    if first >= size {
+        return Ok(None);
+    }
+    return Ok(Some((first, last.min(size - 1))));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ranges(header: &str, size: u64) -> Vec<(u64, u64)> {
+        return match parse(header, size) {
+            RangeSet::Ranges(r) => r,
+            RangeSet::Full => panic!("{} was ignored", header),
+            RangeSet::Unsatisfiable => panic!("{} is unsatisfiable", header),
+        };
+    }
+
+    #[test]
+    fn plain_and_open_ended() {
+        assert_eq!(ranges("bytes=0-9", 100), vec![(0, 9)]);
+        assert_eq!(ranges("bytes=90-", 100), vec![(90, 99)]);
+        assert_eq!(ranges("bytes=90-200", 100), vec![(90, 99)]);
+        assert_eq!(ranges(" bytes= 1 - 2 ", 100), vec![(1, 2)]);
+    }
+
+    #[test]
+    fn suffix() {
+        assert_eq!(ranges("bytes=-10", 100), vec![(90, 99)]);
+        assert_eq!(ranges("bytes=-500", 100), vec![(0, 99)]);
+        assert!(matches!(parse("bytes=-0", 100), RangeSet::Unsatisfiable));
+        assert!(matches!(parse("bytes=-10", 0), RangeSet::Unsatisfiable));
+    }
+
+    #[test]
+    fn overlapping_ranges_are_kept_in_order() {
+        assert_eq!(ranges("bytes=50-59,0-9,5-14", 100),
+                   vec![(50, 59), (0, 9), (5, 14)]);
+    }
+
+    #[test]
+    fn unsatisfiable() {
+        assert!(matches!(parse("bytes=100-", 100), RangeSet::Unsatisfiable));
+        assert!(matches!(parse("bytes=200-300,100-", 100), RangeSet::Unsatisfiable));
+        // One usable range is enough.
+        assert_eq!(ranges("bytes=200-300,0-0", 100), vec![(0, 0)]);
+    }
+
+    #[test]
+    fn invalid_headers_mean_the_whole_file() {
+        for header in ["items=0-9", "bytes=", "bytes=9-0", "bytes=a-b", "bytes=5",
+                       "bytes=0-1,x"] {
+            assert!(matches!(parse(header, 100), RangeSet::Full), "{}", header);
+        }
+        let many = format!("bytes={}", vec!["0-0"; MAX_RANGES + 1].join(","));
+        assert!(matches!(parse(&many, 100), RangeSet::Full));
+    }
+}
@@ -0,0 +1,283 @@
+// Event-driven connection handling (io_mode = "epoll").
+//
+// A few reactor threads multiplex all sockets with mio (epoll on Linux) and
+// only read requests. Once a request head and its body have fully arrived,
+// the socket is switched back to blocking mode and handed to the worker
+// pool together with the parsed request, so slow or idle clients never tie
+// up a worker.
+
+use std::collections::HashMap;
+use std::io;
+use std::io::prelude::*;
+use std::net;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use log::{info, error};
+use mio::{Events, Interest, Poll, Token};
+use mio::net::{TcpListener, TcpStream};
+use crate::ThreadPool;
+use crate::access_log;
+use crate::config_parser::Config;
+use crate::http;
+use crate::server::{self, HeadError};
+
+const LISTENER: Token = Token(0);
+// How often connections are checked for expired timeouts.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+// Pause before accepting again after running out of file descriptors.
+pub(crate) const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+struct Connection {
+    stream: TcpStream,
+    buf: Vec<u8>,
+    // Set once the head has been parsed; `buf` then only holds the body.
+    request: Option<http::Request>,
+    accepted: Instant,
+    body_started: Instant,
+    last_read: Instant,
+}
+
+enum Progress {
+    Waiting,
+    Complete,
+    Failed(&'static [u8]),
+    Closed,
+}
+
+pub fn run(listener: net::TcpListener, pool: ThreadPool, conf: Config,
+           app: fn(request: &http::Request) -> http::HttpResponse) {
+    if let Err(e) = listener.set_nonblocking(true) {
+        error!("{}", e);
+        return;
+    }
+    let pool = Arc::new(pool);
+    let mut handles = Vec::with_capacity(conf.reactor_threads);
+    for id in 0..conf.reactor_threads {
+        let listener = match listener.try_clone() {
+            Ok(l) => TcpListener::from_std(l),
+            Err(e) => {
+                error!("{}", e);
+                return;
+            },
+        };
+        let pool = Arc::clone(&pool);
+        let conf = conf.clone();
+        handles.push(thread::spawn(move || {
+            if let Err(e) = reactor_loop(listener, pool, conf, app) {
+                error!("Reactor {} stopped: {}", id, e);
+            }
+        }));
+    }
+    for handle in handles {
+        let _ = handle.join();
+    }
+}
+
+fn reactor_loop(mut listener: TcpListener, pool: Arc<ThreadPool>, conf: Config,
+                app: fn(request: &http::Request) -> http::HttpResponse
+                ) -> io::Result<()> {
+    let mut poll = Poll::new()?;
+    let mut events = Events::with_capacity(1024);
+    poll.registry().register(&mut listener, LISTENER, Interest::READABLE)?;
+    let mut connections: HashMap<Token, Connection> = HashMap::new();
+    let mut next_token = 1;
+    let mut last_sweep = Instant::now();
+    // Set while accepting is paused, see accept().
+    let mut accept_retry: Option<Instant> = None;
+
+    loop {
+        let wait = match accept_retry {
+            Some(at) => at.saturating_duration_since(Instant::now()).min(SWEEP_INTERVAL),
+            None => SWEEP_INTERVAL,
+        };
+        if let Err(e) = poll.poll(&mut events, Some(wait)) {
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e);
+        }
+        for event in events.iter() {
+            if event.token() == LISTENER {
+                if accept_retry.is_none() &&
+                   !accept(&listener, &poll, &mut connections, &mut next_token) {
+                    accept_retry = Some(Instant::now() + ACCEPT_BACKOFF);
+                }
+                continue;
+            }
+            let token = event.token();
+            let progress = match connections.get_mut(&token) {
+                Some(conn) => read_available(conn, &conf),
+                None => continue,
+            };
+            match progress {
+                Progress::Waiting => (),
+                Progress::Closed => close(&poll, &mut connections, token, None, &conf),
+                Progress::Failed(response) => {
+                    close(&poll, &mut connections, token, Some(response), &conf);
+                },
+                Progress::Complete => {
+                    let mut conn = connections.remove(&token).unwrap();
+                    let _ = poll.registry().deregister(&mut conn.stream);
+                    hand_off(conn, &pool, &conf, app);
+                },
+            }
+        }
+        if accept_retry.is_some_and(|at| Instant::now() >= at) {
+            // The listener is edge-triggered, so connections left in the
+            // backlog won't be reported again on their own.
+            poll.registry().reregister(&mut listener, LISTENER, Interest::READABLE)?;
+            accept_retry = None;
+            if !accept(&listener, &poll, &mut connections, &mut next_token) {
+                accept_retry = Some(Instant::now() + ACCEPT_BACKOFF);
+            }
+        }
+        if last_sweep.elapsed() >= SWEEP_INTERVAL {
+            sweep(&poll, &mut connections, &conf);
+            last_sweep = Instant::now();
+        }
+    }
+}
+
+// Accepts until the backlog is empty. Returns false when accepting has to
+// be paused, e.g. when the process is out of file descriptors (EMFILE).
+fn accept(listener: &TcpListener, poll: &Poll,
+          connections: &mut HashMap<Token, Connection>,
+          next_token: &mut usize) -> bool {
+    loop {
+        let mut stream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(e) => match e.kind() {
+                io::ErrorKind::WouldBlock => return true,
+                // The client gave up before it was accepted.
+                io::ErrorKind::ConnectionAborted |
+                io::ErrorKind::Interrupted => continue,
+                _ => {
+                    error!("Failed to accept a connection: {}", e);
+                    return false;
+                },
+            },
+        };
+        let token = Token(*next_token);
+        *next_token += 1;
+        if let Err(e) = poll.registry().register(&mut stream, token,
+                                                 Interest::READABLE) {
+            error!("{}", e);
+            continue;
+        }
+        let now = Instant::now();
+        connections.insert(token, Connection {
+            stream,
+            buf: Vec::with_capacity(1024),
+            request: None,
+            accepted: now,
+            body_started: now,
+            last_read: now,
+        });
+    }
+}
+
+// Sockets are edge-triggered, so everything available has to be read.
+fn read_available(conn: &mut Connection, conf: &Config) -> Progress {
+    let mut chunk = [0; 8192];
+    loop {
+        let n = match conn.stream.read(&mut chunk) {
+            Ok(0) => return Progress::Closed,
+            Ok(n) => n,
+            Err(e) => {
+                if e.kind() == io::ErrorKind::WouldBlock {
+                    return Progress::Waiting;
+                }
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Progress::Closed;
+            },
+        };
+        conn.last_read = Instant::now();
+        let search_from = conn.buf.len();
+        conn.buf.extend_from_slice(&chunk[..n]);
+        if conn.request.is_none() {
+            match server::check_head(&conn.buf, search_from, conf) {
+                Ok(None) => continue,
+                Ok(Some(head_len)) => {
+                    let body = conn.buf.split_off(head_len);
+                    let head = String::from_utf8_lossy(&conn.buf).into_owned();
+                    let request = http::parse_request(&head, conf);
+                    // Kept even when rejected, for the access log.
+                    let checked = server::check_body(&request, conf);
+                    conn.request = Some(request);
+                    conn.buf = body;
+                    conn.body_started = Instant::now();
+                    if let Err(response) = checked {
+                        return Progress::Failed(response);
+                    }
+                },
+                Err(HeadError::UriTooLong) => {
+                    return Progress::Failed(http::RESPONSE_414);
+                },
+                Err(_) => return Progress::Failed(http::RESPONSE_431),
+            }
+        }
+        if let Some(request) = &conn.request {
+            if conn.buf.len() >= request.content_length {
+                conn.buf.truncate(request.content_length);
+                return Progress::Complete;
+            }
+        }
+    }
+}
+
+fn hand_off(conn: Connection, pool: &ThreadPool, conf: &Config,
+            app: fn(request: &http::Request) -> http::HttpResponse) {
+    let started = conn.accepted;
+    let mut request = conn.request.unwrap();
+    request.body = conn.buf;
+    let stream = net::TcpStream::from(conn.stream);
+    let conf = conf.clone();
+    pool.execute(move || {
+        let r = stream.set_nonblocking(false)
+            .and_then(|_| stream.set_write_timeout(server::timeout(conf.write_timeout)));
+        if let Err(e) = r {
+            error!("Failed to prepare a connection: {}", e);
+            return;
+        }
+        server::serve(stream, request, started, &conf, app);
+    });
+}
+
+// Drops connections that take too long to send their request.
+fn sweep(poll: &Poll, connections: &mut HashMap<Token, Connection>,
+         conf: &Config) {
+    let header_timeout = server::timeout(conf.header_read_timeout);
+    let body_timeout = server::timeout(conf.body_read_timeout);
+    let expired: Vec<Token> = connections.iter().filter(|(_, conn)| {
+        match &conn.request {
+            None => header_timeout.is_some_and(
+                |t| conn.accepted.elapsed() >= t),
+            Some(_) => body_timeout.is_some_and(
+                |t| conn.last_read.elapsed() >= t) ||
+                server::is_too_slow(conn.buf.len(),
+                                    conn.body_started.elapsed(), conf),
+        }
+    }).map(|(token, _)| *token).collect();
+    for token in expired {
+        info!("Timed out waiting for a request");
+        close(poll, connections, token, Some(http::RESPONSE_408), conf);
+    }
+}
+
+// The error responses are tiny, so a single non-blocking write is enough in
+// practice; if it doesn't go through the client just sees the connection
+// close.
+fn close(poll: &Poll, connections: &mut HashMap<Token, Connection>,
+         token: Token, response: Option<&[u8]>, conf: &Config) {
+    if let Some(mut conn) = connections.remove(&token) {
+        if let Some(response) = response {
+            let _ = conn.stream.write(response);
+            access_log::log_response(conn.request.as_ref(), conn.stream.peer_addr().ok(),
+                                     response, conn.accepted, conf);
+        }
+        let _ = poll.registry().deregister(&mut conn.stream);
+    }
+}
@@ -1,18 +1,34 @@
-use std::fs::File;
+use std::io;
 use std::io::prelude::*;
 use std::net::TcpStream;
 use std::net::TcpListener;
 use std::string::String;
-use std::path::Path;
+use std::panic;
+use std::any::Any;
+use std::cell::Cell;
+use std::thread;
+use std::time::{Duration, Instant};
 use log::{LevelFilter, info, error};
-use libflate::gzip;
-use crate::ThreadPool;
+use crate::encoding::{self, Encoding};
+use crate::{ThreadPool, panic_message};
 use crate::config_parser;
 use crate::logger;
-use crate::mime;
 use crate::http;
+use crate::error_pages;
+use crate::access_log::{self, Sent};
+use crate::reactor;
+use crate::static_files::handle_static;
 use crate::default_app::{default_app};
 
+// Slow uploads are only judged by their rate after running this long.
+const MIN_RATE_GRACE: Duration = Duration::from_secs(5);
+
+thread_local! {
+    // Whether the handler running on this thread has started writing its
+    // response. A 500 after that would only corrupt the stream.
+    static RESPONSE_STARTED: Cell<bool> = const { Cell::new(false) };
+}
+
 
 pub fn run_empty() {
     init_listener(default_app);
@@ -23,10 +39,15 @@ pub fn run(app: fn(request: &http::Request) -> http::HttpResponse) {
 }
 
 pub fn init_listener(app: fn(request: &http::Request) -> http::HttpResponse) {
-    log::set_logger(&logger::SIMPLE_LOGGER).unwrap();
+    log::set_logger(&logger::LOGGER).unwrap();
     log::set_max_level(LevelFilter::Info);
 
     let conf = config_parser::get_config();
+    logger::LOGGER.set_json(conf.log_format == "json");
+    if let Err(e) = access_log::open(&conf.access_log) {
+        error!("Failed to open the access log {}: {}", conf.access_log, e);
+        return;
+    }
 
 	let listener = match TcpListener::bind(&conf.address_full) {
         Err(why) => {
@@ -35,11 +56,30 @@ pub fn init_listener(app: fn(request: &http::Request) -> http::HttpResponse) {
         },
         Ok(listener) => listener,
     };
-	let pool = ThreadPool::new(conf.thread_count);
+	let pool = ThreadPool::with_limits(conf.min_threads, conf.max_threads,
+        Duration::from_secs(conf.thread_idle_timeout as u64));
 
+    if conf.io_mode == "epoll" {
+        reactor::run(listener, pool, conf, app);
+        return;
+    }
 	for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(s) => s,
+            Err(e) => match e.kind() {
+                // The client gave up before it was accepted.
+                io::ErrorKind::ConnectionAborted |
+                io::ErrorKind::Interrupted => continue,
+                _ => {
+                    // E.g. out of file descriptors (EMFILE), retrying at
+                    // once would only spin.
+                    error!("Failed to accept a connection: {}", e);
+                    thread::sleep(reactor::ACCEPT_BACKOFF);
+                    continue;
+                },
+            },
+        };
         let conf = conf.clone();
-		let stream = stream.unwrap();
 		pool.execute(move || {
 			handle_connection(stream, conf, app);
 		});
@@ -48,120 +88,368 @@ pub fn init_listener(app: fn(request: &http::Request) -> http::HttpResponse) {
 
 fn handle_connection(mut stream: TcpStream, conf: config_parser::Config,
                      app: fn(request: &http::Request) -> http::HttpResponse) {
-	let mut buffer = [0; 512];
-	stream.read(&mut buffer).unwrap();
-    let request_str = String::from_utf8_lossy(&buffer);
-    let request = http::parse_request(&request_str, &conf);
-    info!("{} {} {}", request.host, request.method, request.url_path);
+    if let Err(e) = stream.set_write_timeout(timeout(conf.write_timeout)) {
+        error!("Failed to set socket timeouts: {}", e);
+        return;
+    }
+    let connected = Instant::now();
+    let (head, received) = match read_head(&mut stream, &conf) {
+        Ok(r) => r,
+        Err(HeadError::Closed) => return,
+        Err(HeadError::Io(e)) => {
+            if is_timeout(&e) {
+                info!("Timed out waiting for request headers");
+                reject(&stream, None, http::RESPONSE_408, connected, &conf);
+            } else {
+                error!("Failed to read a request: {}", e);
+            }
+            return;
+        },
+        Err(HeadError::UriTooLong) => {
+            info!("Request line is longer than {} bytes", conf.max_header_size);
+            reject(&stream, None, http::RESPONSE_414, connected, &conf);
+            return;
+        },
+        Err(HeadError::TooLarge) => {
+            info!("Request headers are larger than {} bytes", conf.max_header_size);
+            reject(&stream, None, http::RESPONSE_431, connected, &conf);
+            return;
+        },
+    };
+    let request_str = String::from_utf8_lossy(&head);
+    let mut request = http::parse_request(&request_str, &conf);
+
+    if let Err(response) = check_body(&request, &conf) {
+        reject(&stream, Some(&request), response, connected, &conf);
+        return;
+    }
+    if request.content_length > 0 {
+        match read_body(&mut stream, received,
+                        request.content_length, &conf) {
+            Ok(body) => request.body = body,
+            Err(e) => {
+                info!("Failed to read request body: {}", e);
+                if is_timeout(&e) {
+                    reject(&stream, Some(&request), http::RESPONSE_408, connected, &conf);
+                }
+                return;
+            },
+        }
+    }
+    serve(stream, request, connected, &conf, app);
+}
+
+// Sends one of the canned error responses, for a request that couldn't be
+// parsed when `request` is None, and logs it.
+fn reject(mut stream: &TcpStream, request: Option<&http::Request>, response: &[u8],
+          started: Instant, conf: &config_parser::Config) {
+    let _ = stream.write_all(response);
+    let _ = stream.flush();
+    access_log::log_response(request, stream.peer_addr().ok(), response, started, conf);
+}
+
+// Runs the app or the static handler for a fully received request.
+// `started` is when the connection was taken up, so the logged time
+// includes reading the request, as in nginx.
+pub(crate) fn serve(stream: TcpStream, request: http::Request, started: Instant,
+                    conf: &config_parser::Config,
+                    app: fn(request: &http::Request) -> http::HttpResponse) {
+    let remote_addr = stream.peer_addr().ok();
+    let sent = logger::with_request(&request, || {
+        guarded(stream, &request, conf, |stream| dispatch(stream, &request, conf, app))
+    });
+    access_log::log(&request, remote_addr, &sent, started, conf);
+    logger::log_completed(&request, sent.status, started);
+}
+
+// Serves a static file for the async server, which logs requests itself.
+#[cfg(feature = "async")]
+pub(crate) fn serve_static(stream: TcpStream, request: &http::Request,
+                           conf: &config_parser::Config) -> Sent {
+    return guarded(stream, request, conf, |stream| handle_static(stream, request, conf));
+}
+
+fn guarded<F: FnOnce(TcpStream) -> Sent>(stream: TcpStream, request: &http::Request,
+                                         conf: &config_parser::Config, f: F) -> Sent {
+    // Keep a handle to the socket so that a 500 can still be sent when the
+    // handler panics after taking ownership of the stream.
+    let err_stream = stream.try_clone();
+    RESPONSE_STARTED.with(|s| s.set(false));
+    let result = panic::catch_unwind(panic::AssertUnwindSafe(|| f(stream)));
+    return match result {
+        Ok(sent) => sent,
+        Err(e) => {
+            log_panic(request, &*e);
+            match err_stream {
+                Ok(s) if !RESPONSE_STARTED.with(|s| s.get()) => {
+                    error_pages::send_error(&s, 500, request, conf)
+                },
+                // The client gets a cut off response, the log the 500.
+                _ => Sent { status: 500, body_bytes: 0 },
+            }
+        },
+    };
+}
 
+// Called by handlers right before they write to the socket.
+pub(crate) fn mark_response_started() {
+    RESPONSE_STARTED.with(|s| s.set(true));
+}
+
+pub(crate) fn log_panic(request: &http::Request, payload: &(dyn Any + Send)) {
+    error!("Panic while handling \"{} {} {}\": {}", request.method,
+           request.url_path, request.version, panic_message(payload));
+}
+
+pub(crate) enum HeadError {
+    Io(io::Error),
+    // The client went away before sending anything.
+    Closed,
+    UriTooLong,
+    TooLarge,
+}
+
+// A request head and the part of the body that arrived with it.
+pub(crate) type Head = (Vec<u8>, Vec<u8>);
+
+// A request head collected from successive reads, for the blocking and the
+// async server alike.
+pub(crate) struct HeadBuffer {
+    buf: Vec<u8>,
+}
+
+impl HeadBuffer {
+    pub(crate) fn new() -> HeadBuffer {
+        return HeadBuffer { buf: Vec::with_capacity(1024) };
+    }
+
+    // Adds the bytes of one read, an empty read meaning the client closed
+    // the connection. Once the head is complete it is returned together
+    // with whatever part of the body arrived with it.
+    pub(crate) fn push(&mut self, data: &[u8], conf: &config_parser::Config
+                       ) -> Result<Option<Head>, HeadError> {
+        if data.is_empty() {
+            if self.buf.is_empty() {
+                return Err(HeadError::Closed);
+            }
+            return Err(HeadError::Io(io::Error::new(io::ErrorKind::UnexpectedEof,
+                "connection closed in the middle of request headers")));
+        }
+        let search_from = self.buf.len();
+        self.buf.extend_from_slice(data);
+        if let Some(head_len) = check_head(&self.buf, search_from, conf)? {
+            let received = self.buf.split_off(head_len);
+            return Ok(Some((std::mem::take(&mut self.buf), received)));
+        }
+        return Ok(None);
+    }
+}
+
+// Reads from the socket until the blank line that ends the request head,
+// growing the buffer up to max_header_size. Returns the head and whatever
+// part of the body arrived with it. The whole head has to arrive within
+// header_read_timeout.
+fn read_head(stream: &mut TcpStream, conf: &config_parser::Config
+             ) -> Result<Head, HeadError> {
+    let deadline = timeout(conf.header_read_timeout)
+        .map(|t| Instant::now() + t);
+    let mut head = HeadBuffer::new();
+    let mut chunk = [0; 1024];
+    loop {
+        if let Some(deadline) = deadline {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left == Duration::from_secs(0) {
+                return Err(HeadError::Io(io::Error::new(io::ErrorKind::TimedOut,
+                    "header_read_timeout expired")));
+            }
+            stream.set_read_timeout(Some(left)).map_err(HeadError::Io)?;
+        }
+        let n = stream.read(&mut chunk).map_err(HeadError::Io)?;
+        if let Some(r) = head.push(&chunk[..n], conf)? {
+            return Ok(r);
+        }
+    }
+}
+
+// Looks for the end of the request head in `buf`, where everything before
+// `search_from` has already been checked. Returns the length of the head
+// once it is complete, or an error when it can't fit into max_header_size.
+pub(crate) fn check_head(buf: &[u8], search_from: usize,
+                         conf: &config_parser::Config
+                         ) -> Result<Option<usize>, HeadError> {
+    // The terminator may straddle two reads.
+    let search_from = search_from.saturating_sub(3);
+    if let Some(p) = find(&buf[search_from..], b"\r\n\r\n") {
+        let head_len = search_from + p + 4;
+        if head_len > conf.max_header_size {
+            return Err(head_limit_error(buf, conf.max_header_size));
+        }
+        return Ok(Some(head_len));
+    }
+    if buf.len() > conf.max_header_size {
+        return Err(head_limit_error(buf, conf.max_header_size));
+    }
+    return Ok(None);
+}
+
+// A request line that alone doesn't fit is reported as a long URI, anything
+// else as oversized headers.
+fn head_limit_error(buf: &[u8], max_header_size: usize) -> HeadError {
+    return match find(buf, b"\r\n") {
+        Some(p) if p <= max_header_size => HeadError::TooLarge,
+        _ => HeadError::UriTooLong,
+    };
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    return haystack.windows(needle.len()).position(|w| w == needle);
+}
+
+// Whether the body the request announces may be read at all. Otherwise
+// the response to send instead.
+pub(crate) fn check_body(request: &http::Request, conf: &config_parser::Config
+                         ) -> Result<(), &'static [u8]> {
+    if request.is_malformed {
+        info!("Malformed request for {}", request.url_path);
+        return Err(http::RESPONSE_400);
+    }
+    if conf.max_body_size > 0 && request.content_length > conf.max_body_size {
+        info!("Request body of {} bytes is larger than {} bytes",
+              request.content_length, conf.max_body_size);
+        return Err(http::RESPONSE_413);
+    }
+    return Ok(());
+}
+
+// A request body collected from successive reads, starting with the part
+// that arrived together with the head. The upload as a whole has to keep up
+// with body_min_rate.
+pub(crate) struct BodyBuffer {
+    body: Vec<u8>,
+    content_length: usize,
+    started: Instant,
+}
+
+impl BodyBuffer {
+    pub(crate) fn new(mut received: Vec<u8>, content_length: usize) -> BodyBuffer {
+        received.truncate(content_length);
+        return BodyBuffer { body: received, content_length, started: Instant::now() };
+    }
+
+    // How much to read next, at most `max` bytes.
+    pub(crate) fn want(&self, max: usize) -> usize {
+        return (self.content_length - self.body.len()).min(max);
+    }
+
+    pub(crate) fn is_complete(&self) -> bool {
+        return self.body.len() >= self.content_length;
+    }
+
+    // Adds the bytes of one read, an empty read meaning the client closed
+    // the connection.
+    pub(crate) fn push(&mut self, data: &[u8], conf: &config_parser::Config
+                       ) -> io::Result<()> {
+        if data.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                "connection closed before the whole body was received"));
+        }
+        self.body.extend_from_slice(data);
+        if is_too_slow(self.body.len(), self.started.elapsed(), conf) {
+            return Err(io::Error::new(io::ErrorKind::TimedOut,
+                format!("upload is slower than {} bytes/s", conf.body_min_rate)));
+        }
+        return Ok(());
+    }
+
+    pub(crate) fn into_body(self) -> Vec<u8> {
+        return self.body;
+    }
+}
+
+// Reads the rest of the request body. Every read is bounded by
+// body_read_timeout.
+fn read_body(stream: &mut TcpStream, received: Vec<u8>, content_length: usize,
+             conf: &config_parser::Config) -> io::Result<Vec<u8>> {
+    stream.set_read_timeout(timeout(conf.body_read_timeout))?;
+    let mut body = BodyBuffer::new(received, content_length);
+    let mut buf = [0; 8192];
+    while !body.is_complete() {
+        let want = body.want(buf.len());
+        let n = stream.read(&mut buf[..want])?;
+        body.push(&buf[..n], conf)?;
+    }
+    return Ok(body.into_body());
+}
+
+pub(crate) fn is_too_slow(received: usize, elapsed: Duration,
+                          conf: &config_parser::Config) -> bool {
+    return conf.body_min_rate > 0 && elapsed >= MIN_RATE_GRACE &&
+           (received as f64 / elapsed.as_secs_f64()) < conf.body_min_rate as f64;
+}
+
+pub(crate) fn timeout(secs: usize) -> Option<Duration> {
+    if secs == 0 {
+        return None;
+    }
+    return Some(Duration::from_secs(secs as u64));
+}
+
+// Depending on the platform an expired socket timeout shows up as either
+// WouldBlock or TimedOut.
+fn is_timeout(e: &io::Error) -> bool {
+    return e.kind() == io::ErrorKind::WouldBlock ||
+           e.kind() == io::ErrorKind::TimedOut;
+}
+
+fn dispatch(mut stream: TcpStream, request: &http::Request,
+            conf: &config_parser::Config,
+            app: fn(request: &http::Request) -> http::HttpResponse) -> Sent {
     if request.is_static {
-        handle_static(stream, &request, &conf);
+        return handle_static(stream, request, conf);
     } else {
-        let response = app(&request);
-        let response_raw = http_response_to_str(&request, &response);
+        let response = app(request);
+        let response_raw = http_response_to_str(request, &response, conf);
+        mark_response_started();
 
-        match stream.write(&response_raw) {
+        match stream.write_all(&response_raw) {
             Ok(_) => (),
-            Err(e) => println!("Failed to send a response: {}", e),
+            Err(e) => error!("Failed to send a response: {}", e),
         };
         match stream.flush() {
             Ok(_) => (),
             Err(e) => error!("{}", e),
         };
+        return Sent::of(&response_raw);
     }
 }
 
-fn http_response_to_str(request: &http::Request, r: &http::HttpResponse
-                        ) ->  std::vec::Vec<u8> {
-    let content: std::vec::Vec<u8>;
-    if request.is_gzip_allowed {
-        let mut encoder = gzip::Encoder::new(Vec::new()).unwrap();
-        match encoder.write_all(&r.content.to_string().into_bytes()) {
-            Ok(_) => (),
-            Err(e) => error!("{}", e),
-        };
-        content = match encoder.finish().into_result() {
-            Ok(d) => d,
-            Err(_) => Vec::new(),
-        };
-    } else {
-        content = r.content.as_bytes().to_vec();
+// An error status without content gets the error page for it.
+pub(crate) fn http_response_to_str(request: &http::Request, r: &http::HttpResponse,
+                                   conf: &config_parser::Config) ->  std::vec::Vec<u8> {
+    if r.code >= 400 && r.content.is_empty() {
+        return error_pages::error_response(r.code, request, conf);
     }
+    let mut available = encoding::ON_THE_FLY.to_vec();
+    available.push(Encoding::Identity);
+    let mut chosen = encoding::negotiate(request.accept_encoding.as_deref(),
+                                         &available);
+    let content = match encoding::encode(chosen, r.content.as_bytes()) {
+        Ok(c) => c,
+        Err(e) => {
+            error!("{}", e);
+            chosen = Encoding::Identity;
+            r.content.as_bytes().to_vec()
+        },
+    };
     let content_len = format!("Content-Length: {}\r\n", content.len());
-    let mut resp = String::from(format!("HTTP/1.1 {} OK\r\nContent-Type: text/html\r\n", r.code));
+    let mut resp = format!("HTTP/1.1 {} {}\r\nContent-Type: text/html\r\n", r.code,
+                           http::reason_phrase(r.code));
     resp.push_str(content_len.as_str());
-    if request.is_gzip_allowed {
-        resp.push_str(&"Content-Encoding: gzip\r\n".to_string());
+    resp.push_str("X-Content-Type-Options: nosniff\r\n");
+    if chosen != Encoding::Identity {
+        resp.push_str(&format!("Content-Encoding: {}\r\n", chosen.name()));
+        resp.push_str("Vary: Accept-Encoding\r\n");
     }
     resp.push_str("\r\n");
     return [resp.into_bytes(), content].concat();
 }
-
-fn handle_static(mut stream: TcpStream, request: &http::Request,
-                conf: &config_parser::Config) {
-    let mut _tmp = [0; 512];
-    stream.read(&mut _tmp).unwrap();
-
-    let mut buf = Vec::new();
-    let content: std::vec::Vec<u8>;
-    let mut f = match File::open(&request.fs_path) {
-        Ok(f) => f,
-        Err(err) => {
-            println!("Unable to open static file: {}", err);
-            http::return_404(&stream);
-            return;
-        }
-    };
-    f.read_to_end(&mut buf).unwrap();
-    let file_size = buf.len();
-    let fs_path = Path::new(&request.fs_path);
-    let file_ext = match fs_path.extension() {
-        None => String::from(""),
-        Some(v) => v.to_str().unwrap().to_owned(),
-    };
-    let is_gzip_needed = request.is_gzip_allowed &&
-                          file_size >= conf.gzip_min_size &&
-                          file_size <= conf.gzip_max_size &&
-                          conf.gzip_file_types.contains(&file_ext);
-    if is_gzip_needed {
-        let mut encoder = gzip::Encoder::new(Vec::new()).unwrap();
-        match encoder.write_all(&buf) {
-            Ok(_) => (),
-            Err(e) => error!("{}", e),
-        };
-        content = match encoder.finish().into_result() {
-            Ok(d) => d,
-            Err(_) => Vec::new(),
-        };
-    } else {
-        content = buf;
-    }
-    let content_len = format!("Content-Length: {}\r\n", content.len());
-    let mime_line = match mime::get_mimetype(request.fs_path.as_str()) {
-        None => String::from(""),
-        Some(m) => format!("Content-Type: {}\r\n", m),
-    };
-    let content_encoding = match is_gzip_needed {
-        true => "Content-Encoding: gzip\r\n",
-        false => "",
-    };
-    let headers = [
-        "HTTP/1.1 200 OK\r\n",
-        content_len.as_str(),
-        mime_line.as_str(),
-        content_encoding,
-        "\r\n"
-    ];
-    let mut response = headers.join("").to_string().into_bytes();
-
-    response.extend(content);
-
-    match stream.write_all(&response) {
-        Ok(_) => (),
-        Err(e) => error!("{}", e),
-    }
-    match stream.flush() {
-        Ok(_) => (),
-        Err(e) => error!("{}", e),
-    };
-}
@@ -0,0 +1,720 @@
+use std::fs::{self, File, Metadata};
+use std::io;
+use std::io::prelude::*;
+use std::io::{BufWriter, SeekFrom};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use log::{info, error};
+use crate::config_parser::{self, StaticMount};
+use crate::encoding::{self, Encoding};
+use crate::mime;
+use crate::error_pages;
+use crate::access_log::Sent;
+use crate::autoindex;
+use crate::http;
+use crate::server;
+use crate::range::{self, RangeSet};
+use crate::file_cache::STATIC_CACHE;
+
+// Buffer size for writing responses and for copying files to the socket.
+const CHUNK_SIZE: usize = 64 * 1024;
+// sendfile(2) transfers at most about 2 GB per call anyway.
+#[cfg(target_os = "linux")]
+const SENDFILE_MAX: usize = 1 << 30;
+
+
+pub(crate) fn handle_static(stream: TcpStream, request: &http::Request,
+                            conf: &config_parser::Config) -> Sent {
+    let mount = &conf.static_mounts[request.static_mount];
+    if request.fs_path.is_empty() {
+        info!("Refused to serve {}", request.url_path);
+        return error_pages::send_error(&stream, 404, request, conf);
+    }
+    if !is_symlink_allowed(&request.fs_path, mount) {
+        info!("Refused to follow a symlink to {}", request.fs_path);
+        return error_pages::send_error(&stream, 404, request, conf);
+    }
+    let (fs_path, meta) = match fs::metadata(&request.fs_path) {
+        Ok(m) if m.is_dir() => {
+            let url_path = request.url_path.split('?').next().unwrap_or("");
+            if !url_path.ends_with('/') {
+                // Relative links in the index only work from the slash form.
+                let location = request.url_path.replacen(url_path,
+                    &format!("{}/", url_path), 1);
+                server::mark_response_started();
+                http::return_301(&stream, &location);
+                return Sent { status: 301, body_bytes: 0 };
+            }
+            match find_index(&request.fs_path, mount) {
+                Some(index) => index,
+                None if mount.autoindex => {
+                    return send_listing(&stream, request, mount, conf);
+                },
+                None => match find_fallback(request, mount, conf) {
+                    Some(fallback) => fallback,
+                    None => {
+                        info!("No index file in {}", request.fs_path);
+                        return error_pages::send_error(&stream, 404, request, conf);
+                    },
+                },
+            }
+        },
+        Ok(m) if m.is_file() => (request.fs_path.clone(), m),
+        Ok(_) => {
+            info!("Unable to open static file: {} is not a file",
+                     request.fs_path);
+            return error_pages::send_error(&stream, 404, request, conf);
+        },
+        Err(err) => match find_fallback(request, mount, conf) {
+            Some(fallback) => fallback,
+            None => {
+                info!("Unable to open static file: {}", err);
+                return error_pages::send_error(&stream, 404, request, conf);
+            },
+        },
+    };
+    let fs_path = fs_path.as_str();
+
+    let ranges = match &request.range {
+        Some(r) if if_range_matches(request, Some(&make_etag(&meta)),
+                                    meta.modified().ok()) => {
+            range::parse(r, meta.len())
+        },
+        _ => RangeSet::Full,
+    };
+    // A Range always refers to the file itself, so encodings are only
+    // considered for plain requests.
+    let (mut encoding, sidecar) = match ranges {
+        RangeSet::Full => choose_encoding(request, fs_path, &meta, mount),
+        _ => (Encoding::Identity, None),
+    };
+    let is_on_the_fly = encoding != Encoding::Identity && sidecar.is_none();
+    let (path, meta) = match sidecar {
+        Some((path, sidecar_meta)) => (path, sidecar_meta),
+        None => (fs_path.to_string(), meta),
+    };
+    let modified = meta.modified().ok();
+    let mut etag = make_etag(&meta);
+    let applied = match is_on_the_fly {
+        true => encoding,
+        false => Encoding::Identity,
+    };
+    let body = match load(&path, &meta, applied, conf) {
+        // An encoded body isn't byte-for-byte the file, so it only gets a
+        // weak validator.
+        Ok(body) if is_on_the_fly => {
+            etag = format!("W/{}", etag);
+            body
+        },
+        Ok(body) => body,
+        Err(LoadError::Encode(e)) => {
+            error!("{}", e);
+            encoding = Encoding::Identity;
+            match load(&path, &meta, Encoding::Identity, conf) {
+                Ok(body) => body,
+                Err(_) => {
+                    return error_pages::send_error(&stream, 404, request, conf);
+                },
+            }
+        },
+        Err(LoadError::Read(err)) => {
+            info!("Unable to open static file: {}", err);
+            return error_pages::send_error(&stream, 404, request, conf);
+        },
+    };
+    let mut headers = validator_headers(Some(&etag), modified);
+    headers.extend(cache_headers(request, fs_path, mount));
+    if encoding != Encoding::Identity || mount.precompressed_static ||
+       is_gzip_type(fs_path, mount) {
+        headers.push("Vary: Accept-Encoding".to_string());
+    }
+
+    let (status, parts) = if is_not_modified(request, Some(&etag), modified) {
+        ("304 Not Modified", Vec::new())
+    } else {
+        // Looked up only now, sniffing reads the file and a 304 has no type.
+        let mime_type = mime::get_content_type(fs_path, conf);
+        let mime_type = mime_type.as_deref();
+        match ranges {
+            RangeSet::Full => {
+                full_response(&body, &mut headers, mime_type, encoding)
+            },
+            RangeSet::Unsatisfiable => {
+                headers.push(format!("Content-Range: bytes */{}", body.len()));
+                ("416 Range Not Satisfiable", Vec::new())
+            },
+            RangeSet::Ranges(ranges) => {
+                partial_response(&body, &ranges, &mut headers, mime_type)
+            },
+        }
+    };
+
+    if let Err(e) = send_response(&stream, status, headers, &parts, &body) {
+        error!("{}", e);
+    }
+    return sent(status, &parts);
+}
+
+// Files that are neither cached nor compressed on the fly are streamed from
+// disk, so memory use doesn't grow with the file size.
+enum Body {
+    Memory(Arc<Vec<u8>>),
+    File(File, u64),
+}
+
+impl Body {
+    fn len(&self) -> u64 {
+        return match self {
+            Body::Memory(buf) => buf.len() as u64,
+            Body::File(_, len) => *len,
+        };
+    }
+}
+
+enum Part {
+    Bytes(Vec<u8>),
+    // Offset and length of a slice of the body.
+    Range(u64, u64),
+}
+
+impl Part {
+    fn len(&self) -> u64 {
+        return match self {
+            Part::Bytes(b) => b.len() as u64,
+            Part::Range(_, len) => *len,
+        };
+    }
+}
+
+enum LoadError {
+    Read(io::Error),
+    Encode(io::Error),
+}
+
+// The body of `path` in `encoding`, from the cache when it's still fresh.
+// Only cacheable files and files compressed on the fly, which are at most
+// gzip_max_size big, are read into memory.
+fn load(path: &str, meta: &Metadata, encoding: Encoding,
+        conf: &config_parser::Config) -> Result<Body, LoadError> {
+    let is_cacheable = conf.static_cache_size > 0 &&
+                       meta.len() <= conf.static_cache_max_file as u64;
+    if is_cacheable {
+        if let Some(body) = STATIC_CACHE.get(path, encoding, meta) {
+            return Ok(Body::Memory(body));
+        }
+    }
+    let mut f = File::open(path).map_err(LoadError::Read)?;
+    if !is_cacheable && encoding == Encoding::Identity {
+        return Ok(Body::File(f, meta.len()));
+    }
+    let mut buf = Vec::new();
+    f.read_to_end(&mut buf).map_err(LoadError::Read)?;
+    if encoding != Encoding::Identity {
+        buf = encoding::encode(encoding, &buf).map_err(LoadError::Encode)?;
+    }
+    let body = Arc::new(buf);
+    if is_cacheable {
+        STATIC_CACHE.insert(path, encoding, meta, Arc::clone(&body),
+                            conf.static_cache_size);
+    }
+    return Ok(Body::Memory(body));
+}
+
+// Negotiates between the precompressed copies next to the file, what can be
+// compressed on the fly and the file as is. Returns the sidecar to serve, if
+// the chosen encoding has one.
+fn choose_encoding(request: &http::Request, fs_path: &str, meta: &Metadata,
+                   mount: &StaticMount
+                   ) -> (Encoding, Option<(String, Metadata)>) {
+    let mut available = Vec::new();
+    let mut sidecars = Vec::new();
+    if mount.precompressed_static {
+        for (encoding, suffix) in [(Encoding::Brotli, "br"), (Encoding::Gzip, "gz")] {
+            if let Some(sidecar) = find_sidecar(fs_path, meta, suffix, mount) {
+                available.push(encoding);
+                sidecars.push((encoding, sidecar));
+            }
+        }
+    }
+    if is_compressible(fs_path, meta.len() as usize, mount) {
+        for encoding in encoding::ON_THE_FLY {
+            if !available.contains(encoding) {
+                available.push(*encoding);
+            }
+        }
+    }
+    available.push(Encoding::Identity);
+    let chosen = encoding::negotiate(request.accept_encoding.as_deref(), &available);
+    let sidecar = sidecars.into_iter().find(|(e, _)| *e == chosen).map(|(_, p)| p);
+    return (chosen, sidecar);
+}
+
+// Looks for foo.js.br / foo.js.gz next to foo.js, skipping copies that are
+// older than the file or that follow_symlinks doesn't allow.
+fn find_sidecar(fs_path: &str, meta: &Metadata, suffix: &str,
+                mount: &StaticMount) -> Option<(String, Metadata)> {
+    let path = format!("{}.{}", fs_path, suffix);
+    let sidecar_meta = match fs::metadata(&path) {
+        Ok(m) if m.is_file() => m,
+        _ => return None,
+    };
+    if !is_symlink_allowed(&path, mount) {
+        return None;
+    }
+    let is_stale = match (sidecar_meta.modified(), meta.modified()) {
+        (Ok(sidecar), Ok(original)) => sidecar < original,
+        _ => false,
+    };
+    if is_stale {
+        return None;
+    }
+    return Some((path, sidecar_meta));
+}
+
+fn is_gzip_type(fs_path: &str, mount: &StaticMount) -> bool {
+    let fs_path = Path::new(fs_path);
+    let file_ext = match fs_path.extension() {
+        None => String::from(""),
+        Some(v) => v.to_str().unwrap().to_owned(),
+    };
+    return mount.gzip_file_types.contains(&file_ext);
+}
+
+fn is_compressible(fs_path: &str, file_size: usize,
+                   mount: &StaticMount) -> bool {
+    return file_size >= mount.gzip_min_size &&
+           file_size <= mount.gzip_max_size &&
+           is_gzip_type(fs_path, mount);
+}
+
+fn send_listing(stream: &TcpStream, request: &http::Request,
+                mount: &StaticMount, conf: &config_parser::Config) -> Sent {
+    let as_json = request.accept.as_deref()
+        .is_some_and(|a| a.contains("application/json"));
+    let is_root = request.url_path.split('?').next() == Some(mount.url.as_str());
+    let (content_type, listing) = match autoindex::render(
+            &request.fs_path, &request.url_path, is_root, as_json,
+            mount.autoindex_hidden, |path| is_symlink_allowed(path, mount)) {
+        Ok(r) => r,
+        Err(e) => {
+            info!("Unable to list {}: {}", request.fs_path, e);
+            return error_pages::send_error(stream, 404, request, conf);
+        },
+    };
+    let headers = vec![
+        format!("Content-Type: {}", content_type),
+        "Cache-Control: no-cache".to_string(),
+        "Vary: Accept".to_string(),
+    ];
+    let body = Body::Memory(Arc::new(listing));
+    let parts = [Part::Range(0, body.len())];
+    if let Err(e) = send_response(stream, "200 OK", headers, &parts, &body) {
+        error!("{}", e);
+    }
+    return sent("200 OK", &parts);
+}
+
+// With client-side routing any URL may be a page of the app, so unknown
+// paths get fallback_file. Paths that look like assets (.js, .png and
+// whatever else has a known non-HTML type) still get a real 404.
+fn find_fallback(request: &http::Request, mount: &StaticMount,
+                 conf: &config_parser::Config) -> Option<(String, Metadata)> {
+    if mount.fallback_file.is_empty() {
+        return None;
+    }
+    let url_path = request.url_path.split('?').next().unwrap_or("");
+    match mime::lookup(url_path, conf) {
+        Some(m) if m != "text/html" => return None,
+        _ => (),
+    }
+    let path = Path::new(&mount.dir).join(&mount.fallback_file);
+    let meta = fs::metadata(&path).ok().filter(|m| m.is_file())?;
+    let path = path.to_str()?.to_string();
+    if !is_symlink_allowed(&path, mount) {
+        return None;
+    }
+    return Some((path, meta));
+}
+
+// The first of index_files that exists in the directory.
+fn find_index(dir: &str, mount: &StaticMount) -> Option<(String, Metadata)> {
+    for name in &mount.index_files {
+        let path = Path::new(dir).join(name);
+        if let Ok(meta) = fs::metadata(&path) {
+            if meta.is_file() && is_symlink_allowed(path.to_str()?, mount) {
+                return Some((path.to_str()?.to_string(), meta));
+            }
+        }
+    }
+    return None;
+}
+
+fn full_response(body: &Body, headers: &mut Vec<String>, mime_type: Option<&str>,
+                 encoding: Encoding) -> (&'static str, Vec<Part>) {
+    if let Some(m) = mime_type {
+        headers.push(format!("Content-Type: {}", m));
+    }
+    if encoding != Encoding::Identity {
+        headers.push(format!("Content-Encoding: {}", encoding.name()));
+    } else {
+        // Ranges always refer to the identity encoding, so they are only
+        // advertised when the file goes out as is.
+        headers.push("Accept-Ranges: bytes".to_string());
+    }
+    return ("200 OK", vec![Part::Range(0, body.len())]);
+}
+
+// Ranges are served from the file as is, never compressed.
+fn partial_response(body: &Body, ranges: &[(u64, u64)], headers: &mut Vec<String>,
+                    mime_type: Option<&str>) -> (&'static str, Vec<Part>) {
+    let size = body.len();
+    if let [(first, last)] = ranges {
+        headers.push(format!("Content-Range: bytes {}-{}/{}", first, last, size));
+        if let Some(m) = mime_type {
+            headers.push(format!("Content-Type: {}", m));
+        }
+        return ("206 Partial Content", vec![Part::Range(*first, last - first + 1)]);
+    }
+
+    let boundary = make_boundary();
+    let mut parts = Vec::new();
+    for (first, last) in ranges {
+        let mut part_head = format!("--{}\r\n", boundary);
+        if let Some(m) = mime_type {
+            part_head.push_str(&format!("Content-Type: {}\r\n", m));
+        }
+        part_head.push_str(&format!("Content-Range: bytes {}-{}/{}\r\n\r\n",
+                                    first, last, size));
+        parts.push(Part::Bytes(part_head.into_bytes()));
+        parts.push(Part::Range(*first, last - first + 1));
+        parts.push(Part::Bytes(b"\r\n".to_vec()));
+    }
+    parts.push(Part::Bytes(format!("--{}--\r\n", boundary).into_bytes()));
+    headers.push(format!("Content-Type: multipart/byteranges; boundary={}",
+                         boundary));
+    return ("206 Partial Content", parts);
+}
+
+// Applies follow_symlinks to every component below the mount's dir, so a link
+// anywhere in the path counts, not only the last one.
+fn is_symlink_allowed(fs_path: &str, mount: &StaticMount) -> bool {
+    if mount.follow_symlinks == "always" {
+        return true;
+    }
+    let relative = match Path::new(fs_path).strip_prefix(&mount.dir) {
+        Ok(r) => r,
+        Err(_) => return false,
+    };
+    let mut path = PathBuf::from(&mount.dir);
+    for component in relative.components() {
+        path.push(component);
+        let link_meta = match fs::symlink_metadata(&path) {
+            Ok(m) => m,
+            // Missing files are dealt with by the caller.
+            Err(_) => return true,
+        };
+        if !link_meta.file_type().is_symlink() {
+            continue;
+        }
+        if mount.follow_symlinks == "never" {
+            return false;
+        }
+        match fs::metadata(&path) {
+            Ok(target) if is_same_owner(&link_meta, &target) => (),
+            _ => return false,
+        }
+    }
+    return true;
+}
+
+#[cfg(unix)]
+fn is_same_owner(a: &Metadata, b: &Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    return a.uid() == b.uid();
+}
+
+#[cfg(not(unix))]
+fn is_same_owner(_a: &Metadata, _b: &Metadata) -> bool {
+    return false;
+}
+
+// Apache-style "inode-size-mtime" validator, cheap to compute and stable as
+// long as the file isn't replaced or modified.
+fn make_etag(meta: &Metadata) -> String {
+    let mtime = meta.modified().ok()
+        .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs()).unwrap_or(0);
+    return format!("\"{:x}-{:x}-{:x}\"", inode(meta), meta.len(), mtime);
+}
+
+#[cfg(unix)]
+fn inode(meta: &Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    return meta.ino();
+}
+
+#[cfg(not(unix))]
+fn inode(_meta: &Metadata) -> u64 {
+    return 0;
+}
+
+fn validator_headers(etag: Option<&str>, modified: Option<SystemTime>) -> Vec<String> {
+    let mut headers = Vec::new();
+    if let Some(etag) = etag {
+        headers.push(format!("ETag: {}", etag));
+    }
+    if let Some(modified) = modified {
+        headers.push(format!("Last-Modified: {}", httpdate::fmt_http_date(modified)));
+    }
+    return headers;
+}
+
+fn cache_headers(request: &http::Request, fs_path: &str,
+                 mount: &StaticMount) -> Vec<String> {
+    let cache_control = match get_cache_control(request, fs_path, mount) {
+        Some(c) => c,
+        None => return Vec::new(),
+    };
+    let mut headers = vec![format!("Cache-Control: {}", cache_control)];
+    if let Some(max_age) = get_max_age(cache_control) {
+        let expires = SystemTime::now() + Duration::from_secs(max_age);
+        headers.push(format!("Expires: {}", httpdate::fmt_http_date(expires)));
+    }
+    return headers;
+}
+
+fn get_cache_control<'a>(request: &http::Request, fs_path: &str,
+                         mount: &'a StaticMount) -> Option<&'a str> {
+    let fs_path = Path::new(fs_path);
+    let file_name = fs_path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+    if !mount.fingerprinted_cache_control.is_empty() && is_fingerprinted(file_name) {
+        return Some(&mount.fingerprinted_cache_control);
+    }
+    let file_ext = fs_path.extension().and_then(|e| e.to_str())
+        .unwrap_or("").to_ascii_lowercase();
+    let url_path = request.url_path.split('?').next().unwrap_or("");
+    let options = glob::MatchOptions {
+        require_literal_separator: true,
+        ..glob::MatchOptions::new()
+    };
+    for rule in &mount.cache_rules {
+        // A rule with both a path and extensions needs both to match.
+        let path_matches = match &rule.path {
+            Some(p) => p.matches_with(url_path, options),
+            None => true,
+        };
+        if path_matches &&
+           (rule.extensions.is_empty() || rule.extensions.contains(&file_ext)) {
+            return Some(&rule.cache_control);
+        }
+    }
+    return None;
+}
+
+// Build tools put a content hash into file names, e.g. app.3f2a9c1b.js or
+// logo-5d41402abc4b.png. Such a file never changes under the same name.
+// A hash has both digits and letters, all-digit parts are more likely dates
+// as in report-20240101.pdf, which may well change.
+fn is_fingerprinted(file_name: &str) -> bool {
+    let stem = match file_name.rfind('.') {
+        Some(p) => &file_name[..p],
+        None => return false,
+    };
+    return stem.split(['.', '-', '_']).skip(1).any(|part| {
+        part.len() >= 8 &&
+        part.chars().all(|c| c.is_ascii_hexdigit()) &&
+        part.chars().any(|c| c.is_ascii_digit()) &&
+        part.chars().any(|c| c.is_ascii_alphabetic())
+    });
+}
+
+fn get_max_age(cache_control: &str) -> Option<u64> {
+    return cache_control.split(',')
+        .filter_map(|d| d.trim().strip_prefix("max-age="))
+        .find_map(|v| v.trim().parse().ok());
+}
+
+// If-None-Match wins over If-Modified-Since when both are sent. ETags are
+// compared weakly, so a compressed copy still validates the identity one.
+fn is_not_modified(request: &http::Request, etag: Option<&str>,
+                   modified: Option<SystemTime>) -> bool {
+    if let Some(value) = &request.if_none_match {
+        let etag = match etag {
+            Some(e) => e.trim_start_matches("W/"),
+            None => return false,
+        };
+        return value.split(',').map(|t| t.trim()).any(|t| {
+            t == "*" || t.trim_start_matches("W/") == etag
+        });
+    }
+    if let Some(value) = &request.if_modified_since {
+        return match (httpdate::parse_http_date(value), modified) {
+            (Ok(date), Some(modified)) => truncate_to_secs(modified) <= date,
+            _ => false,
+        };
+    }
+    return false;
+}
+
+// If-Range makes the Range header conditional: when the file has changed
+// since the client got its copy, the whole file is sent instead. Only strong
+// ETags may match here.
+fn if_range_matches(request: &http::Request, etag: Option<&str>,
+                    modified: Option<SystemTime>) -> bool {
+    let value = match &request.if_range {
+        None => return true,
+        Some(v) => v.trim(),
+    };
+    if value.starts_with('"') {
+        return etag == Some(value);
+    }
+    if value.starts_with("W/") {
+        return false;
+    }
+    return match (httpdate::parse_http_date(value), modified) {
+        (Ok(date), Some(modified)) => date == truncate_to_secs(modified),
+        _ => false,
+    };
+}
+
+// HTTP dates have a one second resolution.
+fn truncate_to_secs(time: SystemTime) -> SystemTime {
+    return match time.duration_since(UNIX_EPOCH) {
+        Ok(d) => UNIX_EPOCH + std::time::Duration::from_secs(d.as_secs()),
+        Err(_) => time,
+    };
+}
+
+fn make_boundary() -> String {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos()).unwrap_or(0);
+    return format!("rase_byteranges_{:x}", nanos);
+}
+
+fn sent(status: &str, parts: &[Part]) -> Sent {
+    return Sent {
+        status: status[..3].parse().unwrap_or(0),
+        body_bytes: parts.iter().map(Part::len).sum(),
+    };
+}
+
+fn send_response(stream: &TcpStream, status: &str, headers: Vec<String>,
+                 parts: &[Part], body: &Body) -> io::Result<()> {
+    let mut head = format!("HTTP/1.1 {}\r\n", status);
+    // Browsers must not second-guess the Content-Type.
+    head.push_str("X-Content-Type-Options: nosniff\r\n");
+    for header in headers {
+        head.push_str(&header);
+        head.push_str("\r\n");
+    }
+    // A 304 describes the cached body, so it must not claim a length of 0.
+    if !status.starts_with("304") {
+        let len: u64 = parts.iter().map(Part::len).sum();
+        head.push_str(&format!("Content-Length: {}\r\n", len));
+    }
+    head.push_str("\r\n");
+
+    // Small responses go out in a single write.
+    server::mark_response_started();
+    let mut writer = BufWriter::with_capacity(CHUNK_SIZE, stream);
+    writer.write_all(head.as_bytes())?;
+    for part in parts {
+        match (part, body) {
+            (Part::Bytes(bytes), _) => writer.write_all(bytes)?,
+            (Part::Range(offset, len), Body::Memory(buf)) => {
+                let (offset, len) = (*offset as usize, *len as usize);
+                writer.write_all(&buf[offset..offset + len])?;
+            },
+            (Part::Range(offset, len), Body::File(file, _)) => {
+                writer.flush()?;
+                send_file(stream, file, *offset, *len)?;
+            },
+        }
+    }
+    return writer.flush();
+}
+
+// Copies a slice of the file to the socket without passing it through user
+// space. Falls back to a plain copy where the file system doesn't support
+// sendfile(2).
+#[cfg(target_os = "linux")]
+fn send_file(stream: &TcpStream, file: &File, offset: u64, len: u64) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    let mut pos = offset as libc::off_t;
+    let mut remaining = len;
+    while remaining > 0 {
+        let count = remaining.min(SENDFILE_MAX as u64) as usize;
+        let n = unsafe {
+            libc::sendfile(stream.as_raw_fd(), file.as_raw_fd(), &mut pos, count)
+        };
+        if n < 0 {
+            let e = io::Error::last_os_error();
+            match e.raw_os_error() {
+                Some(libc::EINTR) => continue,
+                Some(libc::EINVAL) | Some(libc::ENOSYS) if remaining == len => {
+                    return copy_file(stream, file, offset, len);
+                },
+                // The socket is blocking, so this means write_timeout expired.
+                Some(libc::EAGAIN) => return Err(io::Error::new(
+                    io::ErrorKind::TimedOut, "write_timeout expired")),
+                _ => return Err(e),
+            }
+        }
+        if n == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                      "static file shrank while being sent"));
+        }
+        remaining -= n as u64;
+    }
+    return Ok(());
+}
+
+#[cfg(not(target_os = "linux"))]
+fn send_file(stream: &TcpStream, file: &File, offset: u64, len: u64) -> io::Result<()> {
+    return copy_file(stream, file, offset, len);
+}
+
+// Reads and writes CHUNK_SIZE bytes at a time.
+fn copy_file(mut stream: &TcpStream, mut file: &File, offset: u64,
+             len: u64) -> io::Result<()> {
+    file.seek(SeekFrom::Start(offset))?;
+    let mut buf = vec![0; CHUNK_SIZE.min(len as usize)];
+    let mut remaining = len;
+    while remaining > 0 {
+        let want = remaining.min(buf.len() as u64) as usize;
+        let n = match file.read(&mut buf[..want]) {
+            Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                               "static file shrank while being sent")),
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        stream.write_all(&buf[..n])?;
+        remaining -= n as u64;
+    }
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hashed_names_are_fingerprinted() {
+        for name in ["app.3f2a9c1b.js", "logo-5d41402abc4b.png", "chunk_0a1b2c3d.css",
+                     "vendor.min.A1B2C3D4E5.js"] {
+            assert!(is_fingerprinted(name), "{}", name);
+        }
+    }
+
+    #[test]
+    fn other_names_are_not() {
+        for name in ["report-20240101.pdf", "backup_20231015.tar", "app.12345678.js",
+                     "cafebabe.js", "app.deadbeef.js", "app.3f2a9c.js", "3f2a9c1b.js",
+                     "app.3f2a9c1bz.js", "README"] {
+            assert!(!is_fingerprinted(name), "{}", name);
+        }
+    }
+}